@@ -0,0 +1,524 @@
+use crate::data_structures::{
+    Cache, CacheEntry, CacheKey, CacheLimits, FileInfo, Logger, ScanOptions, SizeFormat,
+    SymlinkMode,
+};
+use crate::mounts;
+use crate::{
+    CACHE_MAGIC, DEFAULT_CACHE_MAX_BYTES, decode_cache_data, ensure_cache_dir, get_cache_dir_path,
+    get_cache_path, load_cache, lock_cache, migrate_legacy_cache, read_cache_file, save_cache,
+    write_cache_file,
+};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Instant, SystemTime};
+
+pub fn run_cache_command(program: &str, args: &[String]) -> io::Result<()> {
+    let mut action = None;
+    let mut cache_dir_override: Option<PathBuf> = None;
+    let mut size_format = SizeFormat::Decimal;
+    let mut verbose = false;
+    let mut root: Option<PathBuf> = None;
+    let mut scan_options = ScanOptions::default();
+    let mut cache_limits = CacheLimits {
+        max_entries: None,
+        max_bytes: DEFAULT_CACHE_MAX_BYTES,
+    };
+    let mut cache_max_age: Option<u64> = None;
+    let mut jobs = 1;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verbose" => verbose = true,
+            "-j" => match crate::parse_jobs(args.next().map_or("", |value| value.as_str())) {
+                Ok(value) => jobs = value,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
+            "--ignore-symlinks" => scan_options.ignore_symlinks = true,
+            "-P" => scan_options.symlinks = SymlinkMode::Physical,
+            "-H" => scan_options.symlinks = SymlinkMode::CommandLine,
+            "-L" => scan_options.symlinks = SymlinkMode::Logical,
+            "--count-links" => scan_options.count_links = true,
+            "-x" | "--one-file-system" => scan_options.one_file_system = true,
+            arg if arg.starts_with("--ignore=") => {
+                scan_options.ignore_patterns = crate::parse_ignore_patterns(&arg[9..]);
+            }
+            arg if arg.starts_with("--cache-max-age=") => match crate::parse_duration(&arg[16..]) {
+                Ok(max_age) => cache_max_age = Some(max_age),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
+            arg if arg.starts_with("--cache-max-entries=") => match arg[20..].parse() {
+                Ok(max_entries) => cache_limits.max_entries = Some(max_entries),
+                Err(_) => {
+                    eprintln!("Invalid entry count: {}", &arg[20..]);
+                    return Ok(());
+                }
+            },
+            arg if arg.starts_with("--cache-max-size=") => {
+                match crate::parse_byte_size(&arg[17..]) {
+                    Ok(max_bytes) => cache_limits.max_bytes = max_bytes,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Ok(());
+                    }
+                }
+            }
+            arg if arg.starts_with("--cache-dir=") => {
+                cache_dir_override = Some(PathBuf::from(&arg[12..]));
+            }
+            arg if arg.starts_with("-sf=") => match crate::parse_size_format(&arg[4..]) {
+                Ok(format) => size_format = format,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
+            arg if action.is_none() && !arg.starts_with('-') => action = Some(arg.to_string()),
+            arg if action.as_deref() == Some("warm") && root.is_none() && !arg.starts_with('-') => {
+                root = Some(PathBuf::from(arg));
+            }
+            _ => {
+                print_usage(program);
+                return Ok(());
+            }
+        }
+    }
+
+    let logger = Logger::new(verbose);
+    let cache_dir = get_cache_dir_path(cache_dir_override.as_deref());
+    migrate_legacy_cache(&cache_dir, &logger);
+
+    match action.as_deref() {
+        Some("stats") => show_stats(&cache_dir, &size_format),
+        Some("prune") => prune(&cache_dir, &logger),
+        Some("verify") => verify(&cache_dir),
+        Some("clear") => clear(&cache_dir, &logger),
+        Some("dump") => dump(&cache_dir, &size_format, &logger),
+        Some("warm") => match root {
+            Some(root) => warm(
+                &root,
+                &cache_dir,
+                &scan_options,
+                &cache_limits,
+                cache_max_age,
+                jobs,
+                &size_format,
+                &logger,
+            ),
+            None => {
+                print_usage(program);
+                Ok(())
+            }
+        },
+        _ => {
+            print_usage(program);
+            Ok(())
+        }
+    }
+}
+
+fn print_usage(program: &str) {
+    eprintln!(
+        "Usage: {} cache <stats|prune|verify|clear|dump|warm> [--cache-dir=DIR] [-sf=FORMAT] [--verbose]",
+        program
+    );
+    eprintln!("stats: Show entry counts, file size and age of the cache");
+    eprintln!("prune: Remove entries for unmounted devices or directories that no longer exist");
+    eprintln!("verify: Check the cache file's header, checksum and records");
+    eprintln!("clear: Remove all entries");
+    eprintln!("dump: Print every entry");
+    eprintln!(
+        "       {} cache warm ROOT [-P|-H|-L] [--ignore-symlinks] [--ignore=PATTERNS] [--count-links] [-x] [--cache-max-age=DURATION] [--cache-max-size=SIZE] [--cache-max-entries=N] [-j N]",
+        program
+    );
+    eprintln!(
+        "warm: Calculate and cache the size of every directory below ROOT at low CPU and IO priority"
+    );
+    eprintln!("      Progress is reported as each directory directly below ROOT is finished");
+    eprintln!(
+        "      Use the same -P/-H/-L, --ignore-symlinks, --ignore, --count-links and -x options as the listings it is for"
+    );
+    eprintln!("      -j N: Scan with up to N threads (0: one per CPU)");
+}
+
+fn show_stats(cache_dir: &Path, size_format: &SizeFormat) -> io::Result<()> {
+    let cache_path = get_cache_path(cache_dir);
+    println!("Cache file:      {}", cache_path.display());
+
+    if !cache_path.exists() {
+        println!("Status:          not created yet");
+        return Ok(());
+    }
+
+    let metadata = fs::metadata(&cache_path)?;
+    let data = fs::read(&cache_path)?;
+    println!(
+        "File size:       {}",
+        FileInfo::format_bytes(metadata.len(), size_format)
+    );
+    if let Ok(modified) = metadata.modified() {
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default()
+            .as_secs();
+        println!("Last written:    {} ago", FileInfo::format_age(age));
+    }
+    println!(
+        "Format:          {}",
+        match data.get(CACHE_MAGIC.len()..CACHE_MAGIC.len() + 2) {
+            Some(version) if data.starts_with(CACHE_MAGIC) => {
+                format!("version {}", u16::from_le_bytes([version[0], version[1]]))
+            }
+            _ => "without header (migrated on next save)".to_string(),
+        }
+    );
+
+    let mut cache = match decode_cache_data(&data) {
+        Ok(cache) => cache,
+        Err(reason) => {
+            println!("Status:          corrupted ({})", reason);
+            return Ok(());
+        }
+    };
+
+    println!("Entries:         {}", cache.len());
+    let mut devices: Vec<u64> = cache.entries.keys().map(|key| key.device).collect();
+    devices.sort_unstable();
+    devices.dedup();
+    println!("Devices:         {}", devices.len());
+    let mut option_sets: Vec<u64> = cache.entries.keys().map(|key| key.options).collect();
+    option_sets.sort_unstable();
+    option_sets.dedup();
+    println!("Option sets:     {}", option_sets.len());
+
+    let now = FileInfo::system_time_to_nanos(&SystemTime::now());
+    let describe_use = |last_used: u64| {
+        if last_used == 0 {
+            "never (migrated entry)".to_string()
+        } else {
+            format!(
+                "{} ago",
+                FileInfo::format_age(now.saturating_sub(last_used) / 1_000_000_000)
+            )
+        }
+    };
+    let last_used = cache.entries.values().map(|entry| entry.last_used);
+    if let (Some(oldest), Some(newest)) = (last_used.clone().min(), last_used.max()) {
+        println!("Oldest use:      {}", describe_use(oldest));
+        println!("Newest use:      {}", describe_use(newest));
+    }
+
+    if let Some(filesystems) = mounts::filesystem_ids() {
+        cache.attach_filesystems(filesystems);
+        println!(
+            "Detached:        {} entries for filesystems that are not mounted (removable with `cache prune`)",
+            cache.detached_len()
+        );
+    }
+    Ok(())
+}
+
+fn prune(cache_dir: &Path, logger: &Logger) -> io::Result<()> {
+    ensure_cache_dir(cache_dir)?;
+    let _lock = lock_cache(cache_dir, true)?;
+    let mut cache = read_cache_file(&get_cache_path(cache_dir), logger)?;
+
+    let mounted = mounts::filesystem_ids();
+    if mounted.is_none() {
+        println!(
+            "The mount table is not available on this platform, only recorded paths are checked"
+        );
+    }
+
+    let before = cache.len() + cache.detached_len();
+    for (filesystem, key) in cache.detached.keys() {
+        logger.info(&format!(
+            "Pruning device {} inode {}: filesystem {:016x} not mounted",
+            key.device, key.inode, filesystem
+        ));
+    }
+    cache.detached.clear();
+    cache.entries.retain(|key, entry| {
+        // A recorded path that is gone or now holds a different directory means
+        // the scanned one was deleted (or moved, in which case it gets rescanned).
+        // The path decides where there is one: a device number that isn't in the
+        // mount table can still be in use, e.g. for a btrfs subvolume.
+        let Some(path) = entry.path.as_deref() else {
+            if let Some(mounted) = &mounted
+                && !mounted.contains_key(&key.device)
+            {
+                logger.info(&format!(
+                    "Pruning device {} inode {}: device not mounted",
+                    key.device, key.inode
+                ));
+                return false;
+            }
+            return true;
+        };
+        match FileInfo::cache_key_for_path(path, key.options) {
+            Ok(current_key) if current_key != *key => {
+                logger.info(&format!(
+                    "Pruning {}: path now refers to a different directory",
+                    path.display()
+                ));
+                false
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                logger.info(&format!("Pruning {}: no longer exists", path.display()));
+                false
+            }
+            _ => true,
+        }
+    });
+    let pruned = before - cache.len();
+
+    if pruned > 0 {
+        write_cache_file(&cache, cache_dir, logger)?;
+    }
+    println!("Pruned {} of {} entries", pruned, before);
+    Ok(())
+}
+
+fn verify(cache_dir: &Path) -> io::Result<()> {
+    let cache_path = get_cache_path(cache_dir);
+    if !cache_path.exists() {
+        println!("No cache file at {}", cache_path.display());
+        return Ok(());
+    }
+
+    let data = fs::read(&cache_path)?;
+    match decode_cache_data(&data) {
+        Ok(cache) => {
+            if data.is_empty() || data.starts_with(CACHE_MAGIC) {
+                println!("OK: {} entries", cache.len());
+            } else {
+                println!(
+                    "OK: {} entries in the format without header (migrated on next save)",
+                    cache.len()
+                );
+            }
+            Ok(())
+        }
+        Err(reason) => {
+            println!("Corrupted: {}", reason);
+            process::exit(1);
+        }
+    }
+}
+
+fn clear(cache_dir: &Path, logger: &Logger) -> io::Result<()> {
+    ensure_cache_dir(cache_dir)?;
+    let _lock = lock_cache(cache_dir, true)?;
+    let previous = read_cache_file(&get_cache_path(cache_dir), logger)?;
+
+    // An empty file rather than none, so the old /etc/lss cache is not migrated again
+    write_cache_file(&Cache::new(), cache_dir, logger)?;
+    println!("Cleared {} entries", previous.len());
+    Ok(())
+}
+
+fn dump(cache_dir: &Path, size_format: &SizeFormat, logger: &Logger) -> io::Result<()> {
+    let cache = read_cache_file(&get_cache_path(cache_dir), logger)?;
+
+    let mut entries: Vec<_> = cache.entries.iter().collect();
+    entries.sort_by_key(|(key, _)| **key);
+    let mut detached: Vec<_> = cache.detached.iter().collect();
+    detached.sort_by_key(|(key, _)| **key);
+
+    let print_header = || {
+        println!(
+            "{:<12}{:<12}{:<12}{:<12}{:<12}{:<10}{:<18}Path",
+            "Device", "Inode", "Size", "Local", "On disk", "Items", "Last changed"
+        );
+    };
+    let print_entry = |key: &CacheKey, entry: &CacheEntry| {
+        println!(
+            "{:<12}{:<12}{:<12}{:<12}{:<12}{:<10}{:<18}{}",
+            key.device,
+            key.inode,
+            FileInfo::format_bytes(entry.size_in_bytes(), size_format),
+            FileInfo::format_bytes(entry.local_size, size_format),
+            FileInfo::format_bytes(entry.allocated, size_format),
+            entry.stats.items(),
+            FileInfo::format_timestamp(entry.stats.newest_modified),
+            entry
+                .path
+                .as_ref()
+                .map_or("-".to_string(), |path| path.display().to_string())
+        );
+    };
+
+    print_header();
+    for (key, entry) in entries {
+        print_entry(key, entry);
+    }
+    if !detached.is_empty() {
+        println!();
+        println!("Detached (filesystem not mounted):");
+        print_header();
+        for ((_, key), entry) in detached {
+            print_entry(key, entry);
+        }
+    }
+    println!();
+    println!(
+        "Total entries: {} ({} detached)",
+        cache.len() + cache.detached_len(),
+        cache.detached_len()
+    );
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn warm(
+    root: &Path,
+    cache_dir: &Path,
+    scan_options: &ScanOptions,
+    cache_limits: &CacheLimits,
+    cache_max_age: Option<u64>,
+    jobs: usize,
+    size_format: &SizeFormat,
+    logger: &Logger,
+) -> io::Result<()> {
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| root.display().to_string());
+    let mut root_info =
+        match FileInfo::new(root, name, scan_options.follows_command_line_symlinks()) {
+            Ok(root_info) if root_info.is_directory => root_info,
+            Ok(_) => {
+                eprintln!("{}: not a directory", root.display());
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", root.display(), e);
+                process::exit(1);
+            }
+        };
+
+    lower_priority(logger);
+    let started = Instant::now();
+    let mut cache = load_cache(cache_dir, logger)?;
+    if let Some(max_age) = cache_max_age {
+        cache.set_max_age(max_age);
+    }
+
+    // The top-level subdirectories one at a time, for progress reports; the
+    // final pass over the root then finds all of them fresh in the cache
+    let root_device = FileInfo::device_of(root)?;
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(root)? {
+        // One bad entry shouldn't stop an unattended run, but should show
+        // up in its log even without --verbose
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Could not read an entry of '{}': {}",
+                    root.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        if FileInfo::should_ignore(&path, &scan_options.ignore_patterns) {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Ok(mut subdir) = FileInfo::new(&path, name, scan_options.follows_symlinks())
+            && subdir.is_directory
+        {
+            subdir.detect_mount_point(root_device);
+            // The pass over the root leaves it out with -x too
+            if scan_options.one_file_system && subdir.is_mount_point {
+                continue;
+            }
+            subdirs.push(subdir);
+        }
+    }
+    subdirs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let total = subdirs.len();
+    for (index, mut subdir) in subdirs.into_iter().enumerate() {
+        let size = subdir.calculate_directory_size(&mut cache, false, logger, scan_options, jobs);
+        println!(
+            "[{}/{}] {}: {}, {} directories cached so far",
+            index + 1,
+            total,
+            subdir.full_path.display(),
+            FileInfo::format_bytes(size, size_format),
+            cache.dirty.len()
+        );
+    }
+
+    let size = root_info.calculate_directory_size(&mut cache, false, logger, scan_options, jobs);
+    let cached = cache.dirty.len();
+    save_cache(&mut cache, cache_dir, cache_limits, logger)?;
+
+    println!(
+        "Warmed {}: {} in {} items, {} directories cached in {}",
+        root.display(),
+        FileInfo::format_bytes(size, size_format),
+        root_info.subtree_stats.unwrap_or_default().items(),
+        cached,
+        FileInfo::format_age(started.elapsed().as_secs())
+    );
+    Ok(())
+}
+
+// Lowest CPU priority and the idle IO class, so a scheduled warm-up stays out
+// of the way of interactive work
+#[cfg(target_os = "linux")]
+fn lower_priority(logger: &Logger) {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: both calls only change scheduling attributes of this process
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            logger.warning(&format!(
+                "Could not lower CPU priority: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        if libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        ) != 0
+        {
+            logger.warning(&format!(
+                "Could not lower IO priority: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn lower_priority(logger: &Logger) {
+    // SAFETY: only changes the scheduling priority of this process
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        logger.warning(&format!(
+            "Could not lower CPU priority: {}",
+            io::Error::last_os_error()
+        ));
+    }
+}
+
+#[cfg(not(unix))]
+fn lower_priority(_logger: &Logger) {}
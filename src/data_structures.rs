@@ -1,6 +1,12 @@
+#[cfg(target_os = "linux")]
+use crate::fd_scan::Directory;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SizeFormat {
@@ -11,13 +17,111 @@ pub enum SizeFormat {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileInfo {
+    pub device: u64,
     pub inode: u64,
     pub size: u64,
+    // Space taken on disk (st_blocks * 512); for directories, of everything below
+    pub allocated: u64,
     pub name: String,
     pub file_type: String,
     pub metadata: FileMetadata,
     pub is_directory: bool,
+    // A directory on another filesystem than the one it was listed in
+    pub is_mount_point: bool,
+    // Where a symlink points, and why it couldn't be followed if it was meant to be
+    pub link_target: Option<PathBuf>,
+    pub broken_link: Option<BrokenLink>,
+    // Set on an entry whose metadata couldn't be read; nothing else about it is known
+    pub error: Option<ReadError>,
     pub full_path: PathBuf,
+    // The directory this entry was found in during a scan, still open, so the
+    // scan can open the entry relative to it instead of by path
+    #[cfg(target_os = "linux")]
+    #[serde(skip)]
+    pub parent_directory: Option<Arc<Directory>>,
+    pub modified: u64,
+    pub changed: u64,
+    pub subtree_stats: Option<SubtreeStats>,
+    // Where `size` came from, for --explain
+    pub size_source: Option<SizeSource>,
+    // For a directory, the hard-linked files counted once in its size
+    #[serde(skip)]
+    pub subtree_links: Vec<HardLink>,
+}
+
+// Which sizes the listing shows and sorts by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeMode {
+    // File lengths, as `ls` shows them
+    Apparent,
+    // Allocated blocks, as `du` shows them
+    DiskUsage,
+    // Both, sorted by apparent size
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BrokenLink {
+    // The target doesn't exist
+    Missing,
+    // Resolving it leads back to the link itself
+    Loop,
+    // The target exists but can't be looked at, e.g. for lack of permission
+    Inaccessible,
+}
+
+// Why an entry couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadError {
+    PermissionDenied,
+    // Listed, but gone by the time it was looked at
+    Vanished,
+    Other(String),
+}
+
+// Entries that couldn't be read, by why
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub permission_denied: u32,
+    pub vanished: u32,
+    pub other: u32,
+}
+
+// Which symlinks are followed, as du's -P, -H and -L choose
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkMode {
+    // None; a link counts as itself
+    Physical,
+    // Only the paths given on the command line
+    CommandLine,
+    #[default]
+    Logical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SizeSource {
+    // Everything below came from the cache; the directory was listed this long ago
+    CacheHit { age_secs: u64 },
+    // Nothing was cached, or usable
+    Fresh,
+    // Cached, apart from these changed directories
+    Updated { rescanned: u64, total: u64 },
+    // Some of the subtree couldn't be read or looped back on itself
+    Partial { errors: u32, cycles: u64 },
+    // Calculated as part of a directory above it in a recursive listing
+    Included,
+    // Ctrl-C or --time-budget stopped the scan before these directories were reached
+    Stopped { skipped: u64 },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubtreeStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub max_depth: u32,
+    // Newest mtime of any file or directory, nanoseconds since the Unix epoch
+    pub newest_modified: u64,
+    pub errors: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,15 +145,174 @@ pub enum SizeUnit {
     Tebibytes = 0x0104,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CacheKey {
+    pub device: u64,
+    pub inode: u64,
+    // ScanOptions::cache_tag of the options the entry was computed with
+    pub options: u64,
+}
+
+// Everything that changes what a directory scan adds up
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    // Leave symlinks out of totals entirely, whatever `symlinks` says
+    pub ignore_symlinks: bool,
+    pub symlinks: SymlinkMode,
+    pub ignore_patterns: Vec<String>,
+    // Count a file with several hard links once per link instead of once per total
+    pub count_links: bool,
+    // Don't descend into directories on other filesystems
+    pub one_file_system: bool,
+}
+
+// A file with more than one hard link, found in a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HardLink {
+    pub device: u64,
+    pub inode: u64,
+    pub size: u64,
+    pub allocated: u64,
+    // Its link count, and how many of those links were found so far. Once all
+    // are, no directory outside can have it and it needn't be passed on.
+    pub nlink: u64,
+    pub found: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct CacheEntry {
     pub size: u64,
-    pub inode: u64,
-    pub device_id: u64,
     pub size_unit: SizeUnit,
+    pub modified: u64,
+    pub changed: u64,
+    pub local_size: u64,
+    // Allocated counterparts of `size` and `local_size`
+    pub allocated: u64,
+    pub local_allocated: u64,
+    // Nanoseconds since the Unix epoch, drives LRU eviction
+    pub last_used: u64,
+    // When the directory was last listed, nanoseconds since the Unix epoch
+    pub created: u64,
+    // Where the directory was when it was scanned. Its file name is how the
+    // parent's scan finds it again without listing (known_subdirectories), and
+    // `cache prune` checks whether it still exists. None when unknown or too
+    // long to record, which makes the parent get listed on every scan.
+    pub path: Option<PathBuf>,
+    // Everything below the directory, and just its own non-directory entries
+    pub stats: SubtreeStats,
+    pub local_stats: SubtreeStats,
+    // The directory this one was found in, and the subdirectories found in it
+    pub parent: Option<CacheKey>,
+    pub children: Vec<CacheKey>,
+    // Hard-linked files directly in the directory with links elsewhere, so
+    // totals above it can count each of them once; they are in `local_size`
+    // once too. Empty with --count-links.
+    pub links: Vec<HardLink>,
+    // Id of the filesystem the directory is on (see mounts.rs), 0 if unknown
+    pub filesystem: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Cache {
+    pub entries: HashMap<CacheKey, CacheEntry>,
+    // Keys written during this run; only these override what is on disk at save time
+    pub dirty: HashSet<CacheKey>,
+    // Entries for filesystems that aren't mounted, by filesystem id. Kept and
+    // saved, but never looked up until their filesystem is mounted again.
+    pub detached: HashMap<(u64, CacheKey), CacheEntry>,
+    // Filesystem id of each mounted device, for tagging new entries
+    pub filesystems: HashMap<u64, u64>,
+    // Entries created before this time (nanoseconds since the Unix epoch) are
+    // not used; set from --cache-max-age
+    pub oldest_usable: u64,
+    // Directories scanned through this cache so far
+    pub counts: ScanCounts,
+    // When this run started using the cache, nanoseconds since the Unix
+    // epoch; entries created since were listed by this run
+    pub run_started: u64,
+    // Directories scanned this run whose totals weren't cached, because parts
+    // of them are missing or they hold too many hard links. Never saved.
+    pub uncached: HashMap<CacheKey, UncachedScan>,
+}
+
+// What was found in a directory whose total wasn't cached
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UncachedScan {
+    pub size: u64,
+    pub allocated: u64,
+    // None if it wasn't reached at all
+    pub stats: Option<SubtreeStats>,
+    // Directories below it, or it itself, that the stopped scan didn't reach
+    pub skipped: u64,
+}
+
+// How a scan got each directory's contents
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanCounts {
+    // Unchanged since cached, so the cached entry was used
+    pub reused: u64,
+    // Of those, the ones listed earlier in this run, e.g. through a symlink
+    pub reused_fresh: u64,
+    // Listed because nothing usable was cached
+    pub listed: u64,
+    pub cycles: u64,
+    // Not scanned because the scan was stopped; totals above them are lower bounds
+    pub skipped: u64,
+    // Entries that couldn't be read in the directories that were listed
+    pub errors: ErrorCounts,
 }
 
-pub type Cache = HashMap<String, CacheEntry>;
+// Shared by every thread working on one directory size calculation
+pub struct ScanContext<'a> {
+    pub cache: Mutex<Cache>,
+    pub recalculate: bool,
+    pub logger: &'a Logger,
+    pub options: &'a ScanOptions,
+    pub options_tag: u64,
+    // Threads that may still be started besides the calling one, from -j
+    pub spare_threads: AtomicUsize,
+}
+
+// The directories above the one being scanned, to spot a subdirectory that
+// leads back to one of them
+pub struct AncestorChain<'a> {
+    pub device: u64,
+    pub inode: u64,
+    // How many directories are above this one in the scan
+    pub depth: usize,
+    pub parent: Option<&'a AncestorChain<'a>>,
+}
+
+// What listing one directory found, before its subdirectories are scanned
+#[derive(Debug, Default)]
+pub struct DirectoryListing {
+    pub subdirs: Vec<FileInfo>,
+    // Its plain files, each hard-linked file once
+    pub size: u64,
+    pub allocated: u64,
+    pub links: HashMap<(u64, u64), HardLink>,
+    pub stats: SubtreeStats,
+    pub errors: ErrorCounts,
+}
+
+// What scanning a subtree found, for the directory above it
+#[derive(Debug, Default)]
+pub struct SubtreeScan {
+    pub size: u64,
+    pub counts: ScanCounts,
+    // Hard-linked files anywhere below, each once
+    pub links: HashMap<(u64, u64), HardLink>,
+}
+
+pub struct CacheLimits {
+    pub max_entries: Option<usize>,
+    pub max_bytes: u64,
+}
+
+pub struct ByteReader<'a> {
+    pub data: &'a [u8],
+    pub position: usize,
+}
 
 pub struct Spinner {
     pub frames: Vec<char>,
@@ -67,7 +330,11 @@ pub struct ColumnWidths {
     pub uid: usize,
     pub gid: usize,
     pub size: usize,
+    pub allocated: usize,
+    pub items: usize,
     pub time: usize,
     pub file_type: usize,
     pub name: usize,
+    pub show_source: bool,
+    pub size_mode: SizeMode,
 }
@@ -1,18 +1,44 @@
 use crate::data_structures;
+#[cfg(target_os = "linux")]
+use crate::fd_scan::{Directory, EntryKind, Stat};
+use data_structures::AncestorChain;
+use data_structures::BrokenLink;
+use data_structures::ByteReader;
 use data_structures::Cache;
 use data_structures::CacheEntry;
+use data_structures::CacheKey;
+use data_structures::CacheLimits;
 use data_structures::ColumnWidths;
+use data_structures::DirectoryListing;
+use data_structures::ErrorCounts;
 use data_structures::FileInfo;
 use data_structures::FileMetadata;
+use data_structures::HardLink;
 use data_structures::Logger;
+use data_structures::ReadError;
+use data_structures::ScanContext;
+use data_structures::ScanCounts;
+use data_structures::ScanOptions;
 use data_structures::SizeFormat;
+use data_structures::SizeMode;
+use data_structures::SizeSource;
 use data_structures::SizeUnit;
 use data_structures::Spinner;
+use data_structures::SubtreeScan;
+use data_structures::SubtreeStats;
+use data_structures::SymlinkMode;
+use data_structures::UncachedScan;
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
 use std::time::SystemTime;
 
 // Platform-specific imports
@@ -22,6 +48,14 @@ use std::os::unix::fs::MetadataExt;
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 
+// Levels below a scanned directory whose subdirectories are still opened
+// relative to the directory they were found in
+#[cfg(target_os = "linux")]
+const MAX_OPEN_ANCESTORS: usize = 32;
+
+// Hard-linked files a cache entry records at most
+const MAX_CACHED_LINKS: usize = 4096;
+
 impl FileMetadata {
     fn from_metadata(_metadata: &fs::Metadata) -> Self {
         #[cfg(unix)]
@@ -33,7 +67,7 @@ impl FileMetadata {
                 gid: _metadata.gid(),
             }
         }
-        
+
         #[cfg(windows)]
         {
             // Windows doesn't have these exact concepts, so we use defaults
@@ -48,8 +82,8 @@ impl FileMetadata {
 }
 
 impl SizeUnit {
-    pub fn to_u16(&self) -> u16 {
-        *self as u16
+    pub fn to_u16(self) -> u16 {
+        self as u16
     }
 
     pub fn from_u16(value: u16) -> Option<Self> {
@@ -68,6 +102,561 @@ impl SizeUnit {
     }
 }
 
+impl Cache {
+    pub fn new() -> Self {
+        Self {
+            run_started: FileInfo::system_time_to_nanos(&SystemTime::now()),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<&CacheEntry> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: CacheKey, mut entry: CacheEntry) {
+        entry.filesystem = self.filesystems.get(&key.device).copied().unwrap_or(0);
+        self.dirty.insert(key);
+        self.entries.insert(key, entry);
+    }
+
+    // Adds an entry read from the cache file. Two filesystems may have used
+    // the same device number, so a clash is set aside for attach_filesystems.
+    pub fn restore(&mut self, key: CacheKey, entry: CacheEntry) {
+        match self.entries.entry(key) {
+            Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+            }
+            Entry::Occupied(_) => {
+                self.detached.insert((entry.filesystem, key), entry);
+            }
+        }
+    }
+
+    // Stops using entries created more than `max_age_secs` seconds ago
+    pub fn set_max_age(&mut self, max_age_secs: u64) {
+        self.oldest_usable = FileInfo::system_time_to_nanos(&SystemTime::now())
+            .saturating_sub(max_age_secs.saturating_mul(1_000_000_000));
+    }
+
+    // Whether an entry is recent enough for the --cache-max-age policy
+    pub fn is_usable(&self, entry: &CacheEntry) -> bool {
+        entry.created >= self.oldest_usable
+    }
+
+    pub fn detached_len(&self) -> usize {
+        self.detached.len()
+    }
+
+    // Sorts freshly loaded entries by whether their filesystem is mounted.
+    // `filesystems` maps device ids to filesystem ids. Entries whose filesystem
+    // isn't mounted are detached, so a reused device number can't pick them
+    // up, and ones whose filesystem is back under another device number move
+    // over to it. Entries without a recorded filesystem are left where they are.
+    pub fn attach_filesystems(&mut self, filesystems: HashMap<u64, u64>) {
+        let mut devices: HashMap<u64, u64> = HashMap::new();
+        for (&device, &filesystem) in &filesystems {
+            let mounted_on = devices.entry(filesystem).or_insert(device);
+            *mounted_on = (*mounted_on).min(device);
+        }
+
+        let entries = std::mem::take(&mut self.entries);
+        let detached = std::mem::take(&mut self.detached);
+        let loaded = entries
+            .into_iter()
+            .chain(detached.into_iter().map(|((_, key), entry)| (key, entry)));
+
+        for (key, mut entry) in loaded {
+            let device = match entry.filesystem {
+                0 => Some(key.device),
+                filesystem => devices.get(&filesystem).copied(),
+            };
+            let Some(device) = device else {
+                self.detached.insert((entry.filesystem, key), entry);
+                continue;
+            };
+
+            let moved = |other: CacheKey| {
+                if other.device == key.device {
+                    CacheKey { device, ..other }
+                } else {
+                    other
+                }
+            };
+            let key = moved(key);
+            entry.parent = entry.parent.map(moved);
+            for child in &mut entry.children {
+                *child = moved(*child);
+            }
+
+            match self.entries.get(&key) {
+                Some(existing) if existing.last_used >= entry.last_used => {}
+                _ => {
+                    self.entries.insert(key, entry);
+                }
+            }
+        }
+        self.filesystems = filesystems;
+    }
+
+    // Drops the least recently used entries until the cache fits both limits.
+    // `record_len` gives the encoded size of one entry. Returns how many were evicted.
+    pub fn evict_to_fit(
+        &mut self,
+        limits: &CacheLimits,
+        record_len: impl Fn(&CacheEntry) -> u64,
+    ) -> usize {
+        let mut total_bytes: u64 = self
+            .entries
+            .values()
+            .chain(self.detached.values())
+            .map(&record_len)
+            .sum();
+        let mut total_entries = self.entries.len() + self.detached.len();
+        let max_entries = limits.max_entries.unwrap_or(usize::MAX);
+        if total_entries <= max_entries && total_bytes <= limits.max_bytes {
+            return 0;
+        }
+
+        // Detached entries compete on the same terms; None marks an attached one
+        let mut by_age: Vec<(u64, Option<u64>, CacheKey)> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, None, *key))
+            .chain(
+                self.detached
+                    .iter()
+                    .map(|((filesystem, key), entry)| (entry.last_used, Some(*filesystem), *key)),
+            )
+            .collect();
+        by_age.sort_unstable();
+
+        let mut evicted = 0;
+        for (_, filesystem, key) in by_age {
+            if total_entries <= max_entries && total_bytes <= limits.max_bytes {
+                break;
+            }
+            let removed = match filesystem {
+                None => self.entries.remove(&key),
+                Some(filesystem) => self.detached.remove(&(filesystem, key)),
+            };
+            if let Some(entry) = removed {
+                total_bytes = total_bytes.saturating_sub(record_len(&entry));
+                total_entries -= 1;
+                if filesystem.is_none() {
+                    self.dirty.remove(&key);
+                }
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    // Makes every entry for the given (device, inode) directories, under any
+    // options, look changed so the next scan lists them again. For changes
+    // a directory's timestamps don't show, such as a file rewritten in place.
+    pub fn invalidate(&mut self, directories: &HashSet<(u64, u64)>) {
+        for (key, entry) in self.entries.iter_mut() {
+            if directories.contains(&(key.device, key.inode)) {
+                entry.modified = 0;
+                entry.changed = 0;
+                self.dirty.insert(*key);
+            }
+        }
+    }
+
+    // Applies a change found while rescanning `key` to every cached ancestor
+    // above it, returning how many were updated
+    pub fn propagate_to_ancestors(
+        &mut self,
+        key: &CacheKey,
+        (size_before, size_after): (u64, u64),
+        (allocated_before, allocated_after): (u64, u64),
+        before: &SubtreeStats,
+        after: &SubtreeStats,
+    ) -> usize {
+        let apply = |value: u64, old: u64, new: u64| -> u64 {
+            (value as i128 + new as i128 - old as i128).clamp(0, u64::MAX as i128) as u64
+        };
+
+        let mut updated = 0;
+        let mut seen = HashSet::from([*key]);
+        let mut next = self.get(key).and_then(|entry| entry.parent);
+        let mut distance = 1;
+
+        while let Some(ancestor_key) = next {
+            if !seen.insert(ancestor_key) {
+                break;
+            }
+            let Some(ancestor) = self.entries.get_mut(&ancestor_key) else {
+                break;
+            };
+
+            ancestor.size = apply(ancestor.size_in_bytes(), size_before, size_after);
+            ancestor.size_unit = SizeUnit::Bytes;
+            ancestor.allocated = apply(ancestor.allocated, allocated_before, allocated_after);
+            ancestor.stats.files = apply(ancestor.stats.files, before.files, after.files);
+            ancestor.stats.dirs = apply(ancestor.stats.dirs, before.dirs, after.dirs);
+            ancestor.stats.errors = apply(
+                ancestor.stats.errors as u64,
+                before.errors as u64,
+                after.errors as u64,
+            )
+            .min(u32::MAX as u64) as u32;
+            ancestor.stats.max_depth = ancestor.stats.max_depth.max(after.max_depth + distance);
+            ancestor.stats.newest_modified =
+                ancestor.stats.newest_modified.max(after.newest_modified);
+
+            self.dirty.insert(ancestor_key);
+            updated += 1;
+            distance += 1;
+            next = ancestor.parent;
+        }
+        updated
+    }
+
+    // Folds in a freshly loaded copy of the cache file. Only entries written
+    // during this run are ours to keep; everything else is as the file has it,
+    // so entries another process removed (clear, prune, eviction) stay removed.
+    pub fn merge(&mut self, on_disk: Cache) {
+        let mut entries = on_disk.entries;
+        for key in &self.dirty {
+            if let Some(entry) = self.entries.remove(key) {
+                entries.insert(*key, entry);
+            }
+        }
+        self.entries = entries;
+        self.detached = on_disk.detached;
+    }
+}
+
+impl CacheEntry {
+    pub fn size_in_bytes(&self) -> u64 {
+        // Header-less cache files have no checksum, so a corrupted size can be anything
+        match self.size_unit {
+            SizeUnit::Bytes => self.size,
+            SizeUnit::Kilobytes => self.size.saturating_mul(1000),
+            SizeUnit::Megabytes => self.size.saturating_mul(1_000_000),
+            SizeUnit::Gigabytes => self.size.saturating_mul(1_000_000_000),
+            SizeUnit::Terabytes => self.size.saturating_mul(1_000_000_000_000),
+            SizeUnit::Kibibytes => self.size.saturating_mul(1024),
+            SizeUnit::Mebibytes => self.size.saturating_mul(1_048_576),
+            SizeUnit::Gibibytes => self.size.saturating_mul(1_073_741_824),
+            SizeUnit::Tebibytes => self.size.saturating_mul(1_099_511_627_776),
+        }
+    }
+}
+
+impl ScanOptions {
+    // Identifies the options in cache keys, so a lookup never returns a size
+    // that was added up differently. The order of ignore patterns doesn't matter.
+    pub fn cache_tag(&self) -> u64 {
+        let mut patterns: Vec<&str> = self
+            .ignore_patterns
+            .iter()
+            .map(|pattern| pattern.trim())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+        patterns.sort_unstable();
+        patterns.dedup();
+
+        let mut hasher = Sha256::new();
+        hasher.update([self.ignore_symlinks as u8]);
+        hasher.update((patterns.len() as u64).to_le_bytes());
+        for pattern in patterns {
+            hasher.update((pattern.len() as u64).to_le_bytes());
+            hasher.update(pattern.as_bytes());
+        }
+        if self.count_links {
+            hasher.update(b"count-links");
+        }
+        if self.one_file_system {
+            hasher.update(b"one-file-system");
+        }
+        // -P and -H scan alike; ignored links are never followed either way
+        if !self.ignore_symlinks && self.symlinks != SymlinkMode::Logical {
+            hasher.update(b"physical");
+        }
+        let result = hasher.finalize();
+        u64::from_le_bytes([
+            result[0], result[1], result[2], result[3], result[4], result[5], result[6], result[7],
+        ])
+    }
+
+    // Whether symlinks found while listing or scanning a directory are followed
+    pub fn follows_symlinks(&self) -> bool {
+        !self.ignore_symlinks && self.symlinks == SymlinkMode::Logical
+    }
+
+    // Whether a symlink given as a path on the command line is followed
+    pub fn follows_command_line_symlinks(&self) -> bool {
+        self.symlinks != SymlinkMode::Physical
+    }
+}
+
+impl BrokenLink {
+    // Why following a symlink failed with `error`
+    pub fn from_error(error: &io::Error) -> Self {
+        #[cfg(unix)]
+        if error.raw_os_error() == Some(libc::ELOOP) {
+            return BrokenLink::Loop;
+        }
+        if error.kind() == io::ErrorKind::NotFound {
+            BrokenLink::Missing
+        } else {
+            BrokenLink::Inaccessible
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            BrokenLink::Missing => "broken",
+            BrokenLink::Loop => "link loop",
+            BrokenLink::Inaccessible => "target inaccessible",
+        }
+    }
+}
+
+impl ReadError {
+    pub fn from_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => ReadError::PermissionDenied,
+            io::ErrorKind::NotFound => ReadError::Vanished,
+            _ => ReadError::Other(error.to_string()),
+        }
+    }
+
+    pub fn describe(&self) -> &str {
+        match self {
+            ReadError::PermissionDenied => "permission denied",
+            ReadError::Vanished => "vanished",
+            ReadError::Other(message) => message,
+        }
+    }
+}
+
+impl ErrorCounts {
+    pub fn record(&mut self, error: &ReadError) {
+        let count = match error {
+            ReadError::PermissionDenied => &mut self.permission_denied,
+            ReadError::Vanished => &mut self.vanished,
+            ReadError::Other(_) => &mut self.other,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    pub fn add(&mut self, other: &ErrorCounts) {
+        self.permission_denied = self
+            .permission_denied
+            .saturating_add(other.permission_denied);
+        self.vanished = self.vanished.saturating_add(other.vanished);
+        self.other = self.other.saturating_add(other.other);
+    }
+
+    pub fn total(&self) -> u32 {
+        self.permission_denied
+            .saturating_add(self.vanished)
+            .saturating_add(self.other)
+    }
+
+    // e.g. "2 permission denied, 1 vanished"
+    pub fn summary(&self) -> String {
+        [
+            (self.permission_denied, "permission denied"),
+            (self.vanished, "vanished"),
+            (self.other, "other"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, kind)| format!("{} {}", count, kind))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+impl DirectoryListing {
+    // A plain file; one with several hard links only the first time any of
+    // its names is seen, unless `count_links` is set
+    pub fn add_file(&mut self, file: HardLink, modified: u64, count_links: bool) {
+        self.stats.files += 1;
+        self.stats.newest_modified = self.stats.newest_modified.max(modified);
+        if !count_links && file.nlink > 1 {
+            match self.links.entry((file.device, file.inode)) {
+                Entry::Occupied(mut occupied) => {
+                    occupied.get_mut().found += 1;
+                    if occupied.get().found >= occupied.get().nlink {
+                        occupied.remove();
+                    }
+                    return;
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(file);
+                }
+            }
+        }
+        self.size = self.size.saturating_add(file.size);
+        self.allocated = self.allocated.saturating_add(file.allocated);
+    }
+
+    // Counts an entry that couldn't be read; the first few are logged
+    pub fn report_error(&mut self, logger: &Logger, action: &str, path: &Path, error: &io::Error) {
+        self.errors.record(&ReadError::from_error(error));
+        if self.errors.total() <= 5 {
+            logger.warning(&format!(
+                "Could not {} '{}': {}",
+                action,
+                path.display(),
+                error
+            ));
+        }
+    }
+}
+
+impl ScanCounts {
+    // Adds the counts of a scan that ran separately, e.g. of a subdirectory
+    pub fn add(&mut self, other: &ScanCounts) {
+        self.reused += other.reused;
+        self.reused_fresh += other.reused_fresh;
+        self.listed += other.listed;
+        self.cycles += other.cycles;
+        self.skipped += other.skipped;
+        self.errors.add(&other.errors);
+    }
+}
+
+impl ScanContext<'_> {
+    // A poisoned lock only means another thread panicked, and that panic is
+    // passed on when the threads are joined
+    pub fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Runs `task` on every item, on a new thread while there are spare ones
+    // and on the calling thread otherwise. Results are in the order of `items`.
+    pub fn map_parallel<T, R, F>(&self, items: &mut [T], task: F) -> Vec<R>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(&mut T) -> R + Sync,
+    {
+        if items.len() < 2 || self.spare_threads.load(Ordering::Acquire) == 0 {
+            return items.iter_mut().map(task).collect();
+        }
+
+        enum Pending<'scope, R> {
+            Done(R),
+            Running(thread::ScopedJoinHandle<'scope, R>),
+        }
+
+        let task = &task;
+        thread::scope(|scope| {
+            let pending: Vec<_> = items
+                .iter_mut()
+                .map(|item| {
+                    let spawn = self
+                        .spare_threads
+                        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |spare| {
+                            spare.checked_sub(1)
+                        })
+                        .is_ok();
+                    if spawn {
+                        Pending::Running(scope.spawn(move || {
+                            let result = task(item);
+                            self.spare_threads.fetch_add(1, Ordering::AcqRel);
+                            result
+                        }))
+                    } else {
+                        Pending::Done(task(item))
+                    }
+                })
+                .collect();
+
+            pending
+                .into_iter()
+                .map(|pending| match pending {
+                    Pending::Done(result) => result,
+                    Pending::Running(handle) => handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+                })
+                .collect()
+        })
+    }
+}
+
+impl AncestorChain<'_> {
+    pub fn contains(&self, device: u64, inode: u64) -> bool {
+        let mut link = Some(self);
+        while let Some(ancestor) = link {
+            if ancestor.device == device && ancestor.inode == inode {
+                return true;
+            }
+            link = ancestor.parent;
+        }
+        false
+    }
+}
+
+impl SubtreeStats {
+    pub fn add_subdirectory(&mut self, child: &SubtreeStats) {
+        self.files = self.files.saturating_add(child.files);
+        self.dirs = self.dirs.saturating_add(child.dirs + 1);
+        self.max_depth = self.max_depth.max(child.max_depth + 1);
+        self.newest_modified = self.newest_modified.max(child.newest_modified);
+        self.errors = self.errors.saturating_add(child.errors);
+    }
+
+    pub fn items(&self) -> u64 {
+        self.files + self.dirs
+    }
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.remaining() {
+            return Err(format!(
+                "unexpected end of data at offset {} (wanted {} bytes)",
+                self.position, len
+            ));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
 impl Spinner {
     pub fn new() -> Self {
         Self {
@@ -107,7 +696,7 @@ impl Logger {
             println!("{}", message);
         }
     }
-    
+
     #[allow(dead_code)]
     fn update_loading(&self, spinner: &mut Spinner, message: &str) {
         if !self.verbose {
@@ -124,26 +713,32 @@ impl Logger {
     pub fn progress(&self, spinner: &mut Spinner, current: usize, total: usize, message: &str) {
         if !self.verbose {
             print!("\r{} {} ({}/{}) ", spinner.next(), message, current, total);
-        } else if current % 10 == 0 || current == total {
+        } else if current.is_multiple_of(10) || current == total {
             println!("{} ({}/{})", message, current, total);
         }
     }
 }
 
 impl FileInfo {
-    pub fn new(path: &Path, name: String, ignore_symlinks: bool) -> io::Result<Self> {
-        let metadata = if ignore_symlinks {
-            fs::symlink_metadata(path)?
+    // A symlink that can't be followed is still listed, as the link itself
+    pub fn new(path: &Path, name: String, follow_symlinks: bool) -> io::Result<Self> {
+        let link_metadata = fs::symlink_metadata(path)?;
+        let is_symlink = link_metadata.file_type().is_symlink();
+        let (metadata, broken_link) = if is_symlink && follow_symlinks {
+            match fs::metadata(path) {
+                Ok(metadata) => (metadata, None),
+                Err(e) => (link_metadata, Some(BrokenLink::from_error(&e))),
+            }
         } else {
-            fs::metadata(path)?
+            (link_metadata, None)
         };
 
         let is_directory = metadata.is_dir();
 
-        let file_type = if is_directory {
-            "directory".to_string()
-        } else if metadata.file_type().is_symlink() {
+        let file_type = if is_symlink {
             "symlink".to_string()
+        } else if is_directory {
+            "directory".to_string()
         } else if metadata.is_file() {
             "file".to_string()
         } else {
@@ -151,18 +746,63 @@ impl FileInfo {
         };
 
         let inode = Self::get_inode_from_metadata(&metadata);
+        let (modified, changed) = Self::get_times_from_metadata(&metadata);
 
         Ok(FileInfo {
+            device: Self::get_device_id_for(path, &metadata),
             inode,
             size: metadata.len(),
+            allocated: Self::get_allocated_from_metadata(&metadata),
             name,
             file_type,
             metadata: FileMetadata::from_metadata(&metadata),
             is_directory,
+            is_mount_point: false,
+            link_target: is_symlink.then(|| fs::read_link(path).ok()).flatten(),
+            broken_link,
+            error: None,
             full_path: path.to_path_buf(),
+            #[cfg(target_os = "linux")]
+            parent_directory: None,
+            modified,
+            changed,
+            subtree_stats: None,
+            size_source: None,
+            subtree_links: Vec::new(),
         })
     }
 
+    // An entry that was listed but couldn't be looked at, shown with `error`
+    pub fn unreadable(path: &Path, name: String, error: &io::Error) -> Self {
+        FileInfo {
+            device: 0,
+            inode: 0,
+            size: 0,
+            allocated: 0,
+            name,
+            file_type: "?".to_string(),
+            metadata: FileMetadata {
+                mode: 0,
+                nlink: 0,
+                uid: 0,
+                gid: 0,
+            },
+            is_directory: false,
+            is_mount_point: false,
+            link_target: None,
+            broken_link: None,
+            error: Some(ReadError::from_error(error)),
+            full_path: path.to_path_buf(),
+            #[cfg(target_os = "linux")]
+            parent_directory: None,
+            modified: 0,
+            changed: 0,
+            subtree_stats: None,
+            size_source: None,
+            subtree_links: Vec::new(),
+        }
+    }
+
     #[cfg(unix)]
     fn get_inode_from_metadata(metadata: &fs::Metadata) -> u64 {
         metadata.ino()
@@ -173,211 +813,937 @@ impl FileInfo {
         // On Windows, we'll use creation time + file size as a pseudo-inode
         // This isn't perfect but works for most cases on stable Rust
         use std::time::UNIX_EPOCH;
-        
+
         let created = metadata.created().unwrap_or(UNIX_EPOCH);
-        let created_secs = created.duration_since(UNIX_EPOCH)
+        let created_secs = created
+            .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs();
-        
+
         // Combine creation time and size for a reasonably unique identifier
         created_secs.wrapping_mul(31).wrapping_add(metadata.len())
     }
 
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn get_nlink_from_metadata(metadata: &fs::Metadata) -> u64 {
+        metadata.nlink()
+    }
+
+    #[cfg(windows)]
+    fn get_nlink_from_metadata(_metadata: &fs::Metadata) -> u64 {
+        // Link counts need a file handle on Windows, so hard links count once per link
+        1
+    }
+
+    #[cfg(unix)]
+    fn get_allocated_from_metadata(metadata: &fs::Metadata) -> u64 {
+        // st_blocks is always in 512-byte units, whatever the filesystem's block size
+        metadata.blocks().saturating_mul(512)
+    }
+
+    #[cfg(windows)]
+    fn get_allocated_from_metadata(metadata: &fs::Metadata) -> u64 {
+        // No allocation size in std's metadata on Windows
+        metadata.len()
+    }
+
+    // Returns (mtime, ctime) as nanoseconds since the Unix epoch
+    #[cfg(unix)]
+    fn get_times_from_metadata(metadata: &fs::Metadata) -> (u64, u64) {
+        (
+            Self::timestamp_to_nanos(metadata.mtime(), metadata.mtime_nsec()),
+            Self::timestamp_to_nanos(metadata.ctime(), metadata.ctime_nsec()),
+        )
+    }
+
+    // A stat timestamp as nanoseconds since the Unix epoch, 0 for times before it
+    #[cfg(unix)]
+    pub fn timestamp_to_nanos(secs: i64, nsecs: i64) -> u64 {
+        if secs < 0 {
+            0
+        } else {
+            (secs as u64)
+                .saturating_mul(1_000_000_000)
+                .saturating_add(nsecs as u64)
+        }
+    }
+
+    #[cfg(windows)]
+    fn get_times_from_metadata(metadata: &fs::Metadata) -> (u64, u64) {
+        // Windows has no inode change time, the last write time has to do for both
+        let modified = metadata
+            .modified()
+            .map(|time| Self::system_time_to_nanos(&time))
+            .unwrap_or(0);
+        (modified, modified)
+    }
+
     pub fn calculate_directory_size(
         &mut self,
         cache: &mut Cache,
         recalculate: bool,
-        visited_inodes: &mut HashSet<(u64, u64)>,
         logger: &Logger,
-        ignore_symlinks: bool,
-    ) -> io::Result<u64> {
-        if !self.is_directory {
-            return Ok(self.size);
+        options: &ScanOptions,
+        jobs: usize,
+    ) -> u64 {
+        Self::calculate_directory_sizes(
+            std::slice::from_mut(self),
+            cache,
+            recalculate,
+            logger,
+            options,
+            jobs,
+        );
+        self.size
+    }
+
+    // Calculates the size of every directory in `files`, walking them and the
+    // subtrees below them on up to `jobs` threads
+    pub fn calculate_directory_sizes(
+        files: &mut [FileInfo],
+        cache: &mut Cache,
+        recalculate: bool,
+        logger: &Logger,
+        options: &ScanOptions,
+        jobs: usize,
+    ) {
+        let options_tag = options.cache_tag();
+        let mut directories: Vec<&mut FileInfo> = files
+            .iter_mut()
+            .filter(|file| file.is_directory && !(options.one_file_system && file.is_mount_point))
+            .collect();
+        // Paths below are joined onto these, so the cache records absolute
+        // paths without looking up the working directory for each of them
+        for directory in &mut directories {
+            if let Ok(path) = std::path::absolute(&directory.full_path) {
+                directory.full_path = path;
+            }
+        }
+        let before: Vec<_> = directories
+            .iter()
+            .map(|directory| {
+                cache
+                    .get(&directory.get_cache_key(options_tag))
+                    .map(|entry| (entry.size_in_bytes(), entry.allocated, entry.stats))
+            })
+            .collect();
+
+        let context = ScanContext {
+            cache: Mutex::new(std::mem::take(cache)),
+            recalculate,
+            logger,
+            options,
+            options_tag,
+            spare_threads: AtomicUsize::new(jobs.saturating_sub(1)),
+        };
+        let results = context.map_parallel(&mut directories, |directory| {
+            let parent_key = directory.parent_cache_key(options_tag);
+            directory.calculate_subtree(&context, None, parent_key)
+        });
+        *cache = context
+            .cache
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        for ((directory, scan), before) in directories.into_iter().zip(results).zip(before) {
+            let size = scan.size;
+            cache.counts.add(&scan.counts);
+            directory.subtree_links = scan.links.into_values().collect();
+            directory.record_size_source(cache, &scan.counts, options);
+
+            // Directories above this one aren't rescanned, so bring their cached
+            // totals in line with what changed down here
+            if scan.counts.skipped == 0
+                && let (Some((size_before, allocated_before, stats_before)), Some(stats_after)) =
+                    (before, directory.subtree_stats)
+                && (size_before != size
+                    || allocated_before != directory.allocated
+                    || stats_before != stats_after)
+            {
+                let updated = cache.propagate_to_ancestors(
+                    &directory.get_cache_key(options_tag),
+                    (size_before, size),
+                    (allocated_before, directory.allocated),
+                    &stats_before,
+                    &stats_after,
+                );
+                if updated > 0 {
+                    logger.info(&format!(
+                        "Updated {} cached ancestor directories of '{}'",
+                        updated, directory.name
+                    ));
+                }
+            }
         }
+    }
+
+    fn parent_cache_key(&self, options_tag: u64) -> Option<CacheKey> {
+        let parent_path = match self.full_path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Some(Path::new(".")),
+            parent => parent,
+        };
+        parent_path.and_then(|parent| Self::cache_key_for_path(parent, options_tag).ok())
+    }
 
-        let current_key = (self.inode, self.get_device_id());
-        if visited_inodes.contains(&current_key) {
+    fn calculate_subtree(
+        &mut self,
+        context: &ScanContext,
+        ancestors: Option<&AncestorChain>,
+        parent_key: Option<CacheKey>,
+    ) -> SubtreeScan {
+        let logger = context.logger;
+        let options = context.options;
+        let options_tag = context.options_tag;
+        let device_id = self.get_device_id();
+        let mut counts = ScanCounts::default();
+        if ancestors.is_some_and(|chain| chain.contains(device_id, self.inode)) {
             logger.warning(&format!(
                 "Detected directory cycle at {}",
                 self.full_path.display()
             ));
-            return Ok(0);
-        }
-        visited_inodes.insert(current_key.clone());
-
-        let cache_key = self.get_cache_key();
-
-        if !recalculate {
-            if let Some(entry) = cache.get(&cache_key) {
-                if self.get_device_id() == entry.device_id {
-                    self.size = match entry.size_unit {
-                        SizeUnit::Bytes => entry.size,
-                        SizeUnit::Kilobytes => entry.size * 1000,
-                        SizeUnit::Megabytes => entry.size * 1_000_000,
-                        SizeUnit::Gigabytes => entry.size * 1_000_000_000,
-                        SizeUnit::Terabytes => entry.size * 1_000_000_000_000,
-                        SizeUnit::Kibibytes => entry.size * 1024,
-                        SizeUnit::Mebibytes => entry.size * 1_048_576,
-                        SizeUnit::Gibibytes => entry.size * 1_073_741_824,
-                        SizeUnit::Tebibytes => entry.size * 1_099_511_627_776,
+            counts.cycles += 1;
+            return SubtreeScan {
+                counts,
+                ..SubtreeScan::default()
+            };
+        }
+        let cache_key = CacheKey {
+            device: device_id,
+            inode: self.inode,
+            options: options_tag,
+        };
+
+        // Nothing is known below a directory that wasn't reached in time
+        if crate::STOP_SCAN.load(Ordering::Relaxed) {
+            counts.skipped += 1;
+            self.size = 0;
+            self.allocated = 0;
+            context.cache().uncached.insert(
+                cache_key,
+                UncachedScan {
+                    skipped: 1,
+                    ..UncachedScan::default()
+                },
+            );
+            return SubtreeScan {
+                counts,
+                ..SubtreeScan::default()
+            };
+        }
+        let chain = AncestorChain {
+            device: device_id,
+            inode: self.inode,
+            depth: ancestors.map_or(0, |parent| parent.depth + 1),
+            parent: ancestors,
+        };
+
+        // An unchanged mtime/ctime means no entry was added, removed or renamed
+        // here since the cached scan, so the size of the plain files can be
+        // reused and only the subdirectories need another look.
+        let cached = if context.recalculate {
+            None
+        } else {
+            let cache = context.cache();
+            cache
+                .get(&cache_key)
+                .filter(|entry| self.times_equal(entry) && cache.is_usable(entry))
+                .cloned()
+        };
+
+        if let Some(entry) = &cached {
+            counts.reused += 1;
+            if entry.created >= context.cache().run_started {
+                counts.reused_fresh += 1;
+            }
+        } else {
+            counts.listed += 1;
+        }
+
+        let mut listing = DirectoryListing {
+            stats: SubtreeStats {
+                newest_modified: self.modified,
+                ..SubtreeStats::default()
+            },
+            ..DirectoryListing::default()
+        };
+
+        // With the membership unchanged, the recorded subdirectories are all
+        // there is to look at, which saves listing directories full of files
+        let known_subdirs = cached.as_ref().and_then(|entry| {
+            self.known_subdirectories(context, &entry.children, &entry.local_stats)
+        });
+
+        let subdirs = match known_subdirs {
+            Some(subdirs) => subdirs,
+            None => {
+                #[cfg(target_os = "linux")]
+                let listed =
+                    self.list_directory_at(cached.is_none(), &mut listing, logger, options);
+                #[cfg(not(target_os = "linux"))]
+                let listed = self.list_directory(cached.is_none(), &mut listing, logger, options);
+                if let Err(e) = listed {
+                    logger.warning(&format!(
+                        "Could not read directory '{}': {}",
+                        self.full_path.display(),
+                        e
+                    ));
+                    counts.errors.record(&ReadError::from_error(&e));
+                    // Nothing below is known, which makes the size a lower bound
+                    self.size = 0;
+                    self.allocated = 0;
+                    let stats = SubtreeStats {
+                        errors: 1,
+                        ..SubtreeStats::default()
+                    };
+                    self.subtree_stats = Some(stats);
+                    context.cache().uncached.insert(
+                        cache_key,
+                        UncachedScan {
+                            stats: Some(stats),
+                            ..UncachedScan::default()
+                        },
+                    );
+                    return SubtreeScan {
+                        counts,
+                        ..SubtreeScan::default()
                     };
-                    visited_inodes.remove(&current_key);
-                    return Ok(self.size);
                 }
+                std::mem::take(&mut listing.subdirs)
             }
+        };
+        // Each subdirectory keeps the directory it was found in open until it
+        // is scanned, which adds up to one descriptor per level (and thread).
+        // Deep down they are opened by path instead, so a deep tree can't run
+        // out of descriptors.
+        #[cfg(target_os = "linux")]
+        let subdirs = if chain.depth >= MAX_OPEN_ANCESTORS {
+            subdirs
+                .into_iter()
+                .map(|subdir| FileInfo {
+                    parent_directory: None,
+                    ..subdir
+                })
+                .collect()
+        } else {
+            subdirs
+        };
+        let DirectoryListing {
+            size: mut local_size,
+            allocated: mut local_allocated,
+            links: mut local_links,
+            stats: mut local_stats,
+            errors,
+            ..
+        } = listing;
+
+        let mut subdir_stats = Vec::new();
+
+        // A subdirectory that is also one of our ancestors, e.g. through a symlink.
+        // Still an entry here, but leaving it out of `children` makes the next
+        // scan list this directory again and see the cycle.
+        let (mut subdirs, cycles): (Vec<_>, Vec<_>) = subdirs
+            .into_iter()
+            .partition(|subdir| !chain.contains(subdir.get_device_id(), subdir.inode));
+        for subdir_info in cycles {
+            logger.warning(&format!(
+                "Detected directory cycle at {}",
+                subdir_info.full_path.display()
+            ));
+            counts.cycles += 1;
+            subdir_stats.push(SubtreeStats::default());
         }
 
-        let mut total_size = 0u64;
-        let mut entry_count = 0;
-        let mut error_count = 0;
+        let results = context.map_parallel(&mut subdirs, |subdir_info| {
+            subdir_info.calculate_subtree(context, Some(&chain), Some(cache_key))
+        });
 
-        let entries = match fs::read_dir(&self.full_path) {
-            Ok(entries) => entries,
-            Err(e) => {
-                logger.warning(&format!(
-                    "Could not read directory '{}': {}",
-                    self.full_path.display(),
-                    e
-                ));
-                visited_inodes.remove(&current_key);
-                return Ok(0);
+        if let Some(entry) = &cached {
+            local_links = entry
+                .links
+                .iter()
+                .map(|link| ((link.device, link.inode), *link))
+                .collect();
+        }
+
+        let mut subdirs_size = 0u64;
+        let mut subdirs_allocated = 0u64;
+        // Counted in more than one of the subtrees added up here, but only once
+        // in this directory's total
+        let mut links = local_links.clone();
+        let (mut shared_size, mut shared_allocated) = (0u64, 0u64);
+        let mut children = Vec::new();
+        {
+            let cache = context.cache();
+            for (subdir_info, subdir_scan) in subdirs.iter().zip(results) {
+                subdirs_size = subdirs_size.saturating_add(subdir_scan.size);
+                counts.add(&subdir_scan.counts);
+                for (id, link) in subdir_scan.links {
+                    match links.entry(id) {
+                        Entry::Occupied(mut occupied) => {
+                            shared_size = shared_size.saturating_add(link.size);
+                            shared_allocated = shared_allocated.saturating_add(link.allocated);
+                            occupied.get_mut().found += link.found;
+                            if occupied.get().found >= occupied.get().nlink {
+                                occupied.remove();
+                            }
+                        }
+                        Entry::Vacant(vacant) => {
+                            vacant.insert(link);
+                        }
+                    }
+                }
+                subdirs_allocated = subdirs_allocated.saturating_add(subdir_info.allocated);
+                subdir_stats.push(subdir_info.subtree_stats.unwrap_or_default());
+                // Incomplete, unreadable or with too many hard links to cache;
+                // what was found still counts
+                let child_key = subdir_info.get_cache_key(options_tag);
+                if subdir_scan.counts.skipped == 0 && cache.get(&child_key).is_some() {
+                    children.push(child_key);
+                }
             }
+        }
+
+        let error_count = errors.total();
+        counts.errors.add(&errors);
+        if error_count > 5 {
+            logger.warning(&format!(
+                "{} additional errors in '{}'",
+                error_count - 5,
+                self.full_path.display()
+            ));
+        }
+
+        match &cached {
+            Some(entry) => {
+                local_size = entry.local_size;
+                local_allocated = entry.local_allocated;
+                local_stats = entry.local_stats;
+            }
+            None => local_stats.errors = error_count,
+        }
+        local_stats.dirs = subdir_stats.len() as u64;
+
+        let total_size = local_size
+            .saturating_add(subdirs_size)
+            .saturating_sub(shared_size);
+        let total_allocated = local_allocated
+            .saturating_add(subdirs_allocated)
+            .saturating_sub(shared_allocated);
+        let mut stats = SubtreeStats {
+            dirs: 0,
+            ..local_stats
         };
+        for child in &subdir_stats {
+            stats.add_subdirectory(child);
+        }
+        if cached.is_some() {
+            logger.info(&format!(
+                "Directory '{}': unchanged since cached scan, total size: {} bytes",
+                self.name, total_size
+            ));
+        } else {
+            logger.info(&format!(
+                "Directory '{}': {} entries, {} errors, total size: {} bytes",
+                self.name,
+                local_stats.files + local_stats.dirs,
+                error_count,
+                total_size
+            ));
+        }
+
+        self.size = total_size;
+        self.allocated = total_allocated;
+        self.subtree_stats = Some(stats);
+
+        // Caching a total with parts missing would pass it off as complete
+        if counts.skipped > 0 {
+            context.cache().uncached.insert(
+                cache_key,
+                UncachedScan {
+                    size: total_size,
+                    allocated: total_allocated,
+                    stats: Some(stats),
+                    skipped: counts.skipped,
+                },
+            );
+            return SubtreeScan {
+                size: total_size,
+                counts,
+                links,
+            };
+        }
+
+        // Hard-linked files with links elsewhere are recorded one by one, so a
+        // directory full of them (a backup snapshot, say) would take up much
+        // of the cache on its own. It is listed on every scan instead.
+        if local_links.len() > MAX_CACHED_LINKS {
+            context.cache().uncached.insert(
+                cache_key,
+                UncachedScan {
+                    size: total_size,
+                    allocated: total_allocated,
+                    stats: Some(stats),
+                    skipped: 0,
+                },
+            );
+            return SubtreeScan {
+                size: total_size,
+                counts,
+                links,
+            };
+        }
+
+        // The files counted here are only as recent as the listing they came from
+        let now = Self::system_time_to_nanos(&SystemTime::now());
+        let created = cached.as_ref().map_or(now, |entry| entry.created);
+
+        context.cache().insert(
+            cache_key,
+            CacheEntry {
+                size: total_size,
+                size_unit: SizeUnit::Bytes,
+                modified: self.modified,
+                changed: self.changed,
+                local_size,
+                allocated: total_allocated,
+                local_allocated,
+                last_used: now,
+                created,
+                path: self.full_path.is_absolute().then(|| self.full_path.clone()),
+                stats,
+                local_stats,
+                parent: parent_key,
+                children,
+                links: local_links.into_values().collect(),
+                filesystem: 0,
+            },
+        );
 
-        for entry in entries {
+        SubtreeScan {
+            size: total_size,
+            counts,
+            links,
+        }
+    }
+
+    // Reads the directory, adding up its plain files when `count_files` is set
+    // and collecting the subdirectories to descend into
+    #[cfg(not(target_os = "linux"))]
+    fn list_directory(
+        &self,
+        count_files: bool,
+        listing: &mut DirectoryListing,
+        logger: &Logger,
+        options: &ScanOptions,
+    ) -> io::Result<()> {
+        let ignore_symlinks = options.ignore_symlinks;
+        let follow_symlinks = options.follows_symlinks();
+        let own_device = if options.one_file_system {
+            Some(Self::device_of(&self.full_path)?)
+        } else {
+            None
+        };
+        for entry in fs::read_dir(&self.full_path)? {
             let entry = match entry {
                 Ok(entry) => entry,
                 Err(e) => {
-                    error_count += 1;
-                    if error_count <= 5 {
-                        logger.warning(&format!(
-                            "Could not read entry in '{}': {}",
-                            self.full_path.display(),
-                            e
-                        ));
-                    }
+                    listing.report_error(logger, "read entry in", &self.full_path, &e);
                     continue;
                 }
             };
 
             let path = entry.path();
-            entry_count += 1;
 
-            let metadata_result = if ignore_symlinks {
-                fs::symlink_metadata(&path)
+            if !options.ignore_patterns.is_empty()
+                && Self::should_ignore(&path, &options.ignore_patterns)
+            {
+                continue;
+            }
+
+            if !count_files {
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => {}
+                    Ok(file_type) if file_type.is_symlink() && follow_symlinks => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        listing.report_error(logger, "get file type for", &path, &e);
+                        continue;
+                    }
+                }
+            }
+
+            // A link that can't be followed counts as itself; a directory
+            // reached through one that leads back up is caught as a cycle
+            let metadata_result = if follow_symlinks {
+                fs::metadata(&path).or_else(|e| match fs::symlink_metadata(&path) {
+                    Ok(metadata) if metadata.file_type().is_symlink() => Ok(metadata),
+                    _ => Err(e),
+                })
             } else {
-                fs::metadata(&path)
+                fs::symlink_metadata(&path)
             };
 
             match metadata_result {
                 Ok(metadata) => {
+                    if own_device.is_some_and(|device| {
+                        Self::get_device_id_from_metadata(&metadata) != device
+                    }) {
+                        continue;
+                    }
                     if metadata.is_dir() {
                         let entry_inode = Self::get_inode_from_metadata(&metadata);
                         let entry_device = Self::get_device_id_from_metadata(&metadata);
-                        
-                        if entry_inode == self.inode && entry_device == self.get_device_id() {
-                            continue;
-                        }
 
-                        let subdir_key = (entry_inode, entry_device);
-                        if visited_inodes.contains(&subdir_key) {
+                        if entry_inode == self.inode && entry_device == self.get_device_id() {
                             continue;
                         }
 
                         let name = entry.file_name().to_string_lossy().to_string();
-                        match FileInfo::new(&path, name, ignore_symlinks) {
-                            Ok(mut subdir_info) => {
-                                match subdir_info.calculate_directory_size(
-                                    cache,
-                                    recalculate,
-                                    visited_inodes,
-                                    logger,
-                                    ignore_symlinks,
-                                ) {
-                                    Ok(subdir_size) => {
-                                        total_size = total_size.saturating_add(subdir_size);
-                                    }
-                                    Err(e) => {
-                                        error_count += 1;
-                                        if error_count <= 5 {
-                                            logger.warning(&format!(
-                                                "Could not calculate size for '{}': {}",
-                                                path.display(),
-                                                e
-                                            ));
-                                        }
-                                    }
-                                }
-                            }
+                        match FileInfo::new(&path, name, follow_symlinks) {
+                            Ok(subdir_info) => listing.subdirs.push(subdir_info),
                             Err(e) => {
-                                error_count += 1;
-                                if error_count <= 5 {
-                                    logger.warning(&format!(
-                                        "Could not create FileInfo for '{}': {}",
-                                        path.display(),
-                                        e
-                                    ));
-                                }
+                                listing.report_error(logger, "create FileInfo for", &path, &e)
                             }
                         }
-                    } else {
+                    } else if count_files {
                         if ignore_symlinks && metadata.file_type().is_symlink() {
                             continue;
                         }
-                        total_size = total_size.saturating_add(metadata.len());
+                        listing.add_file(
+                            HardLink {
+                                device: Self::get_device_id_from_metadata(&metadata),
+                                inode: Self::get_inode_from_metadata(&metadata),
+                                size: metadata.len(),
+                                allocated: Self::get_allocated_from_metadata(&metadata),
+                                nlink: Self::get_nlink_from_metadata(&metadata),
+                                found: 1,
+                            },
+                            Self::get_times_from_metadata(&metadata).0,
+                            options.count_links,
+                        );
                     }
                 }
-                Err(e) => {
-                    error_count += 1;
-                    if error_count <= 5 {
-                        logger.warning(&format!(
-                            "Could not get metadata for '{}': {}",
-                            path.display(),
-                            e
-                        ));
+                Err(e) => listing.report_error(logger, "get metadata for", &path, &e),
+            }
+        }
+        Ok(())
+    }
+
+    // The same as list_directory, through file descriptors: the directory is
+    // opened relative to the one it was found in, its entries are looked at
+    // relative to it, and entries d_type rules out aren't looked at at all
+    #[cfg(target_os = "linux")]
+    fn list_directory_at(
+        &self,
+        count_files: bool,
+        listing: &mut DirectoryListing,
+        logger: &Logger,
+        options: &ScanOptions,
+    ) -> io::Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let ignore_symlinks = options.ignore_symlinks;
+        let follow_symlinks = options.follows_symlinks();
+        let directory = Arc::new(self.open_directory(follow_symlinks)?);
+
+        for entry in directory.entries()? {
+            let name = OsStr::from_bytes(entry.name.to_bytes());
+            let path = || self.full_path.join(name);
+
+            if !options.ignore_patterns.is_empty()
+                && Self::should_ignore(&path(), &options.ignore_patterns)
+            {
+                continue;
+            }
+            if ignore_symlinks && entry.kind == EntryKind::Symlink {
+                continue;
+            }
+            if !count_files {
+                match entry.kind {
+                    EntryKind::Directory | EntryKind::Unknown => {}
+                    EntryKind::Symlink if follow_symlinks => {}
+                    _ => continue,
+                }
+            }
+
+            // A link that can't be followed counts as itself
+            let stat = if follow_symlinks {
+                directory.stat_at(&entry.name, true).or_else(|e| {
+                    match directory.stat_at(&entry.name, false) {
+                        Ok(stat) if stat.is_symlink() => Ok(stat),
+                        _ => Err(e),
                     }
+                })
+            } else {
+                directory.stat_at(&entry.name, false)
+            };
+            let stat = match stat {
+                Ok(stat) => stat,
+                Err(e) => {
+                    listing.report_error(logger, "get metadata for", &path(), &e);
+                    continue;
                 }
+            };
+
+            if options.one_file_system && stat.device != self.device {
+                continue;
+            }
+            if stat.is_directory() {
+                if stat.inode == self.inode && stat.device == self.device {
+                    continue;
+                }
+                listing.subdirs.push(FileInfo::from_stat(
+                    path(),
+                    name.to_string_lossy().to_string(),
+                    &stat,
+                    entry.kind == EntryKind::Symlink,
+                    Arc::clone(&directory),
+                ));
+            } else if count_files {
+                if ignore_symlinks && stat.is_symlink() {
+                    continue;
+                }
+                listing.add_file(
+                    HardLink {
+                        device: stat.device,
+                        inode: stat.inode,
+                        size: stat.size,
+                        allocated: stat.blocks.saturating_mul(512),
+                        nlink: stat.nlink,
+                        found: 1,
+                    },
+                    stat.modified,
+                    options.count_links,
+                );
             }
         }
+        Ok(())
+    }
 
-        if error_count > 5 {
-            logger.warning(&format!(
-                "{} additional errors in '{}'",
-                error_count - 5,
-                self.full_path.display()
+    // This directory, relative to the one it was found in where that is still open
+    #[cfg(target_os = "linux")]
+    fn open_directory(&self, follow_symlinks: bool) -> io::Result<Directory> {
+        let directory = match (&self.parent_directory, self.full_path.file_name()) {
+            (Some(parent), Some(name)) => parent.open_at(name, follow_symlinks)?,
+            _ => Directory::open(&self.full_path)?,
+        };
+        // Renamed or replaced since it was found, so not the directory to scan
+        let opened = directory.stat()?;
+        if opened.device != self.device || opened.inode != self.inode {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "replaced while being scanned",
             ));
         }
+        Ok(directory)
+    }
 
-        self.size = total_size;
-
-        cache.insert(
-            cache_key,
-            CacheEntry {
-                size: total_size,
-                inode: self.inode,
-                device_id: self.get_device_id(),
-                size_unit: SizeUnit::Bytes,
+    // A subdirectory found by list_directory_at or known_subdirectories
+    #[cfg(target_os = "linux")]
+    fn from_stat(
+        path: std::path::PathBuf,
+        name: String,
+        stat: &Stat,
+        is_symlink: bool,
+        parent_directory: Arc<Directory>,
+    ) -> Self {
+        FileInfo {
+            device: stat.device,
+            inode: stat.inode,
+            size: stat.size,
+            allocated: stat.blocks.saturating_mul(512),
+            name,
+            file_type: if is_symlink { "symlink" } else { "directory" }.to_string(),
+            metadata: FileMetadata {
+                mode: stat.mode,
+                nlink: stat.nlink,
+                uid: stat.uid,
+                gid: stat.gid,
             },
-        );
+            is_directory: true,
+            is_mount_point: false,
+            link_target: None,
+            broken_link: None,
+            error: None,
+            full_path: path,
+            parent_directory: Some(parent_directory),
+            modified: stat.modified,
+            changed: stat.changed,
+            subtree_stats: None,
+            size_source: None,
+            subtree_links: Vec::new(),
+        }
+    }
 
-        visited_inodes.remove(&current_key);
+    // The subdirectories recorded for this directory, found again under their
+    // recorded names. None if any of them can't be, so the caller lists instead.
+    fn known_subdirectories(
+        &self,
+        context: &ScanContext,
+        children: &[CacheKey],
+        cached_local_stats: &SubtreeStats,
+    ) -> Option<Vec<FileInfo>> {
+        if children.len() as u64 != cached_local_stats.dirs {
+            return None;
+        }
 
-        logger.info(&format!(
-            "Directory '{}': {} entries, {} errors, total size: {} bytes",
-            self.name, entry_count, error_count, total_size
-        ));
+        let names = {
+            let cache = context.cache();
+            children
+                .iter()
+                .map(|child_key| Some(cache.get(child_key)?.path.as_ref()?.file_name()?.to_owned()))
+                .collect::<Option<Vec<_>>>()?
+        };
 
-        Ok(total_size)
+        let follow_symlinks = context.options.follows_symlinks();
+        #[cfg(target_os = "linux")]
+        let directory = Arc::new(self.open_directory(follow_symlinks).ok()?);
+
+        let mut subdirs = Vec::with_capacity(children.len());
+        for (child_key, name) in children.iter().zip(names) {
+            #[cfg(target_os = "linux")]
+            let subdir_info = {
+                use std::os::unix::ffi::OsStrExt;
+
+                let c_name = std::ffi::CString::new(name.as_bytes()).ok()?;
+                let mut stat = directory.stat_at(&c_name, false).ok()?;
+                let is_symlink = stat.is_symlink();
+                if is_symlink && follow_symlinks {
+                    stat = directory.stat_at(&c_name, true).ok()?;
+                }
+                if !stat.is_directory() {
+                    return None;
+                }
+                FileInfo::from_stat(
+                    self.full_path.join(&name),
+                    name.to_string_lossy().to_string(),
+                    &stat,
+                    is_symlink,
+                    Arc::clone(&directory),
+                )
+            };
+            #[cfg(not(target_os = "linux"))]
+            let subdir_info = {
+                let subdir_info = FileInfo::new(
+                    &self.full_path.join(&name),
+                    name.to_string_lossy().to_string(),
+                    follow_symlinks,
+                )
+                .ok()?;
+                if !subdir_info.is_directory {
+                    return None;
+                }
+                subdir_info
+            };
+            if subdir_info.get_cache_key(child_key.options) != *child_key {
+                return None;
+            }
+            subdirs.push(subdir_info);
+        }
+        Some(subdirs)
     }
-    
-    #[allow(dead_code)]
-    fn times_equal(&self, _other: &SystemTime) -> bool {
-        true
+
+    // Shows what an earlier scan found below this directory, as long as the
+    // directory itself hasn't changed since, without walking it again
+    pub fn load_cached_stats(&mut self, cache: &Cache, options: &ScanOptions) {
+        if !self.is_directory {
+            return;
+        }
+        if let Some(entry) = cache.get(&self.get_cache_key(options.cache_tag()))
+            && self.times_equal(entry)
+            && cache.is_usable(entry)
+        {
+            self.subtree_stats = Some(entry.stats);
+        }
     }
-    
-    #[allow(dead_code)]
-    fn system_time_to_secs(&self, _time: &SystemTime) -> u64 {
-        1
+
+    // Takes the size of a directory that was just calculated as part of one
+    // above it from the cache, instead of walking it again. Below a directory
+    // whose scan was stopped (`parent_stopped`), one that wasn't reached at
+    // all counts as skipped.
+    pub fn load_cached_size(&mut self, cache: &Cache, options: &ScanOptions, parent_stopped: bool) {
+        if !self.is_directory {
+            return;
+        }
+        let key = self.get_cache_key(options.cache_tag());
+        if let Some(scan) = cache.uncached.get(&key) {
+            self.size = scan.size;
+            self.allocated = scan.allocated;
+            self.subtree_stats = scan.stats;
+            let errors = scan.stats.map_or(0, |stats| stats.errors);
+            self.size_source = Some(if scan.skipped > 0 {
+                SizeSource::Stopped {
+                    skipped: scan.skipped,
+                }
+            } else if errors > 0 {
+                SizeSource::Partial { errors, cycles: 0 }
+            } else {
+                SizeSource::Included
+            });
+        } else if let Some(entry) = cache.get(&key)
+            && self.times_equal(entry)
+            && cache.is_usable(entry)
+        {
+            self.size = entry.size_in_bytes();
+            self.allocated = entry.allocated;
+            self.subtree_stats = Some(entry.stats);
+            self.size_source = Some(if entry.stats.errors > 0 {
+                SizeSource::Partial {
+                    errors: entry.stats.errors,
+                    cycles: 0,
+                }
+            } else {
+                SizeSource::Included
+            });
+        } else if parent_stopped {
+            self.size = 0;
+            self.allocated = 0;
+            self.size_source = Some(SizeSource::Stopped { skipped: 1 });
+        }
+    }
+
+    // Works out where the size from the last scan of this directory came
+    // from, given how the directories below it were scanned
+    fn record_size_source(&mut self, cache: &Cache, counts: &ScanCounts, options: &ScanOptions) {
+        let errors = self.subtree_stats.map_or(0, |stats| stats.errors);
+        let now = Self::system_time_to_nanos(&SystemTime::now());
+
+        self.size_source = Some(if counts.skipped > 0 {
+            SizeSource::Stopped {
+                skipped: counts.skipped,
+            }
+        } else if errors > 0 || counts.cycles > 0 {
+            SizeSource::Partial {
+                errors,
+                cycles: counts.cycles,
+            }
+        } else if counts.reused == counts.reused_fresh {
+            // Nothing in the subtree was cached before this run
+            SizeSource::Fresh
+        } else if counts.listed == 0 && counts.reused_fresh == 0 {
+            let created = cache
+                .get(&self.get_cache_key(options.cache_tag()))
+                .map_or(now, |entry| entry.created);
+            SizeSource::CacheHit {
+                age_secs: now.saturating_sub(created) / 1_000_000_000,
+            }
+        } else {
+            SizeSource::Updated {
+                rescanned: counts.listed + counts.reused_fresh,
+                total: counts.listed + counts.reused,
+            }
+        });
+    }
+
+    fn times_equal(&self, entry: &CacheEntry) -> bool {
+        self.modified == entry.modified && self.changed == entry.changed
+    }
+
+    pub fn system_time_to_nanos(time: &SystemTime) -> u64 {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_nanos().min(u64::MAX as u128) as u64)
+            .unwrap_or(0)
     }
 
     pub fn should_ignore(path: &Path, ignore_patterns: &[String]) -> bool {
@@ -389,50 +1755,72 @@ impl FileInfo {
 
             if pattern.ends_with('/') || pattern.ends_with('\\') {
                 let dir_pattern = pattern.trim_end_matches('/').trim_end_matches('\\');
-                if path.is_dir() {
-                    if let Some(file_name) = path.file_name() {
-                        if file_name.to_string_lossy() == dir_pattern {
-                            return true;
-                        }
-                    }
-                }
-            } else if let Some(file_name) = path.file_name() {
-                if file_name.to_string_lossy() == pattern {
+                if path.is_dir()
+                    && let Some(file_name) = path.file_name()
+                    && file_name.to_string_lossy() == dir_pattern
+                {
                     return true;
                 }
+            } else if let Some(file_name) = path.file_name()
+                && file_name.to_string_lossy() == pattern
+            {
+                return true;
             }
         }
         false
     }
 
-    fn get_cache_key(&self) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(self.inode.to_string().as_bytes());
-        hasher.update(self.get_device_id().to_string().as_bytes());
-        format!("{:x}", hasher.finalize())
+    pub fn get_cache_key(&self, options_tag: u64) -> CacheKey {
+        CacheKey {
+            device: self.get_device_id(),
+            inode: self.inode,
+            options: options_tag,
+        }
+    }
+
+    pub fn device_of(path: &Path) -> io::Result<u64> {
+        fs::metadata(path).map(|metadata| Self::get_device_id_from_metadata(&metadata))
+    }
+
+    // Marks a directory on another filesystem than `parent_device`, the one it
+    // was listed in. Symlinks to such directories aren't mount points.
+    pub fn detect_mount_point(&mut self, parent_device: u64) {
+        self.is_mount_point = self.is_directory
+            && fs::symlink_metadata(&self.full_path).is_ok_and(|metadata| {
+                metadata.is_dir() && Self::get_device_id_from_metadata(&metadata) != parent_device
+            });
+    }
+
+    // The key a directory at `path` would be cached under right now
+    pub fn cache_key_for_path(path: &Path, options_tag: u64) -> io::Result<CacheKey> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(FileInfo::new(path, name, true)?.get_cache_key(options_tag))
     }
 
-    #[cfg(unix)]
     fn get_device_id(&self) -> u64 {
-        if let Ok(metadata) = fs::metadata(&self.full_path) {
-            metadata.dev()
-        } else {
-            0
-        }
+        self.device
+    }
+
+    #[cfg(unix)]
+    fn get_device_id_for(_path: &Path, metadata: &fs::Metadata) -> u64 {
+        metadata.dev()
     }
 
     #[cfg(windows)]
-    fn get_device_id(&self) -> u64 {
+    fn get_device_id_for(path: &Path, _metadata: &fs::Metadata) -> u64 {
         // Use a hash of the drive/volume path as device ID
         use sha2::{Digest, Sha256};
-        
-        if let Some(prefix) = self.full_path.components().next() {
+
+        if let Some(prefix) = path.components().next() {
             let mut hasher = Sha256::new();
             hasher.update(format!("{:?}", prefix).as_bytes());
             let result = hasher.finalize();
             u64::from_le_bytes([
-                result[0], result[1], result[2], result[3],
-                result[4], result[5], result[6], result[7],
+                result[0], result[1], result[2], result[3], result[4], result[5], result[6],
+                result[7],
             ])
         } else {
             0
@@ -484,7 +1872,7 @@ impl FileInfo {
         {
             // Windows simplified permissions display
             let mut permissions = String::with_capacity(10);
-            
+
             permissions.push(if self.is_directory {
                 'd'
             } else if self.file_type == "symlink" {
@@ -501,21 +1889,170 @@ impl FileInfo {
         }
     }
 
+    // Rough human-readable length of a span of seconds, e.g. "3h 12m"
+    pub fn format_age(secs: u64) -> String {
+        match secs {
+            0..60 => format!("{}s", secs),
+            60..3_600 => format!("{}m", secs / 60),
+            3_600..86_400 => format!("{}h {}m", secs / 3_600, secs % 3_600 / 60),
+            _ => format!("{}d {}h", secs / 86_400, secs % 86_400 / 3_600),
+        }
+    }
+
+    pub fn format_size_source(&self) -> String {
+        match &self.size_source {
+            None if self.is_mount_point => "other filesystem, not calculated".to_string(),
+            None if self.is_directory => "not calculated".to_string(),
+            None => "-".to_string(),
+            Some(SizeSource::CacheHit { age_secs }) => {
+                format!("cache hit, listed {} ago", Self::format_age(*age_secs))
+            }
+            Some(SizeSource::Fresh) => "fresh scan".to_string(),
+            Some(SizeSource::Updated { rescanned, total }) => {
+                format!("updated, rescanned {} of {} directories", rescanned, total)
+            }
+            Some(SizeSource::Included) => "included in parent's scan".to_string(),
+            Some(SizeSource::Stopped { skipped }) => format!(
+                "stopped early, {} {} not scanned",
+                skipped,
+                if *skipped == 1 {
+                    "directory"
+                } else {
+                    "directories"
+                }
+            ),
+            Some(SizeSource::Partial { errors, cycles }) => {
+                let mut reasons = Vec::new();
+                if *errors > 0 {
+                    reasons.push(format!("{} unreadable", errors));
+                }
+                if *cycles > 0 {
+                    reasons.push(format!(
+                        "{} {} skipped",
+                        cycles,
+                        if *cycles == 1 { "cycle" } else { "cycles" }
+                    ));
+                }
+                format!("partial, {}", reasons.join(", "))
+            }
+        }
+    }
+
+    // UTC "YYYY-MM-DD HH:MM" for a nanosecond Unix timestamp
+    pub fn format_timestamp(nanos: u64) -> String {
+        let secs = nanos / 1_000_000_000;
+        let days = (secs / 86_400) as i64;
+        let minutes_of_day = (secs % 86_400) / 60;
+
+        // Civil-from-days conversion for the proleptic Gregorian calendar
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            year,
+            month,
+            day,
+            minutes_of_day / 60,
+            minutes_of_day % 60
+        )
+    }
+
+    // For directories with known subtree stats this is the newest change anywhere below
     fn format_time(&self) -> String {
-        "1".into()
+        let modified = self.subtree_stats.map_or(self.modified, |stats| {
+            stats.newest_modified.max(self.modified)
+        });
+        Self::format_timestamp(modified)
+    }
+
+    fn format_items(&self) -> String {
+        self.subtree_stats
+            .map_or("-".to_string(), |stats| stats.items().to_string())
     }
 
     fn format_size(&self, size_format: &SizeFormat) -> String {
+        self.format_lower_bound(Self::format_bytes(self.size, size_format))
+    }
+
+    fn format_allocated(&self, size_format: &SizeFormat) -> String {
+        self.format_lower_bound(Self::format_bytes(self.allocated, size_format))
+    }
+
+    fn format_lower_bound(&self, size: String) -> String {
+        if self.is_incomplete() {
+            format!("≥ {}", size)
+        } else {
+            size
+        }
+    }
+
+    // Calculated with parts of the subtree unreadable or not reached before the
+    // scan was stopped, so the size is a lower bound
+    pub fn is_incomplete(&self) -> bool {
+        match self.size_source {
+            Some(SizeSource::Partial { errors, .. }) => errors > 0,
+            Some(SizeSource::Stopped { .. }) => true,
+            _ => false,
+        }
+    }
+
+    // The hard-linked files counted in this entry's size, itself for a file
+    pub fn hard_links(&self, options: &ScanOptions) -> Vec<HardLink> {
+        if self.is_directory {
+            self.subtree_links.clone()
+        } else if self.metadata.nlink > 1 && self.error.is_none() && !options.count_links {
+            vec![HardLink {
+                device: self.get_device_id(),
+                inode: self.inode,
+                size: self.size,
+                allocated: self.allocated,
+                nlink: self.metadata.nlink,
+                found: 1,
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Much less allocated than its length: holes in a sparse file, or for a
+    // directory, sparse or compressed files below it
+    pub fn is_sparse(&self) -> bool {
+        const MIN_UNALLOCATED: u64 = 1024 * 1024;
+        self.allocated < self.size / 2 && self.size - self.allocated >= MIN_UNALLOCATED
+    }
+
+    // What sorting by size goes by
+    pub fn size_for(&self, size_mode: SizeMode) -> u64 {
+        match size_mode {
+            SizeMode::DiskUsage => self.allocated,
+            SizeMode::Apparent | SizeMode::Both => self.size,
+        }
+    }
+
+    pub fn format_bytes(size: u64, size_format: &SizeFormat) -> String {
         match size_format {
-            SizeFormat::Bytes => format!("{}", self.size),
-            SizeFormat::Binary => self.format_size_binary(),
-            SizeFormat::Decimal => self.format_size_decimal(),
+            SizeFormat::Bytes => format!("{}", size),
+            SizeFormat::Binary => Self::format_size_binary(size),
+            SizeFormat::Decimal => Self::format_size_decimal(size),
         }
     }
 
-    fn format_size_binary(&self) -> String {
+    fn format_size_binary(size: u64) -> String {
         const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
-        let mut size = self.size as f64;
+        let mut size = size as f64;
         let mut unit_index = 0;
 
         while size >= 1024.0 && unit_index < UNITS.len() - 1 {
@@ -530,9 +2067,9 @@ impl FileInfo {
         }
     }
 
-    fn format_size_decimal(&self) -> String {
+    fn format_size_decimal(size: u64) -> String {
         const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
-        let mut size = self.size as f64;
+        let mut size = size as f64;
         let mut unit_index = 0;
 
         while size >= 1000.0 && unit_index < UNITS.len() - 1 {
@@ -547,6 +2084,14 @@ impl FileInfo {
         }
     }
 
+    // The name, and where it points for a symlink, as `ls -l` shows them
+    pub fn display_name(&self) -> String {
+        match &self.link_target {
+            Some(target) => format!("{} -> {}", self.name, target.display()),
+            None => self.name.clone(),
+        }
+    }
+
     fn get_display_fields(
         &self,
         size_format: &SizeFormat,
@@ -559,7 +2104,41 @@ impl FileInfo {
         String,
         String,
         String,
+        String,
+        String,
     ) {
+        if let Some(error) = &self.error {
+            let unknown = "?".to_string();
+            return (
+                unknown.clone(),
+                "?".repeat(10),
+                unknown.clone(),
+                unknown.clone(),
+                unknown.clone(),
+                unknown.clone(),
+                unknown.clone(),
+                unknown.clone(),
+                unknown,
+                format!("error ({})", error.describe()),
+            );
+        }
+        let mut notes = Vec::new();
+        if let Some(broken_link) = self.broken_link {
+            notes.push(broken_link.describe());
+        } else if self.file_type == "symlink" && self.is_directory {
+            notes.push("directory");
+        }
+        if self.is_mount_point {
+            notes.push("mount point");
+        }
+        if self.is_sparse() {
+            notes.push("sparse");
+        }
+        let file_type = if notes.is_empty() {
+            self.file_type.clone()
+        } else {
+            format!("{} ({})", self.file_type, notes.join(", "))
+        };
         (
             format!("{}", self.inode),
             self.format_permissions(),
@@ -567,8 +2146,10 @@ impl FileInfo {
             format!("{}", self.metadata.uid),
             format!("{}", self.metadata.gid),
             self.format_size(size_format),
+            self.format_allocated(size_format),
+            self.format_items(),
             self.format_time(),
-            self.file_type.clone(),
+            file_type,
         )
     }
 }
@@ -582,15 +2163,19 @@ impl ColumnWidths {
             uid: 8,
             gid: 8,
             size: 10,
+            allocated: 10,
+            items: 7,
             time: 12,
             file_type: 10,
             name: 20,
+            show_source: false,
+            size_mode: SizeMode::Apparent,
         }
     }
 
     pub fn calculate_from_files(&mut self, files: &[FileInfo], size_format: &SizeFormat) {
         for file in files {
-            let (inode, permissions, links, uid, gid, size, time, file_type) =
+            let (inode, permissions, links, uid, gid, size, allocated, items, time, file_type) =
                 file.get_display_fields(size_format);
 
             self.inode = self.inode.max(inode.len());
@@ -599,9 +2184,11 @@ impl ColumnWidths {
             self.uid = self.uid.max(uid.len());
             self.gid = self.gid.max(gid.len());
             self.size = self.size.max(size.len());
+            self.allocated = self.allocated.max(allocated.len());
+            self.items = self.items.max(items.len());
             self.time = self.time.max(time.len());
             self.file_type = self.file_type.max(file_type.len());
-            self.name = self.name.max(file.name.len());
+            self.name = self.name.max(file.display_name().len());
         }
 
         self.inode += 2;
@@ -610,21 +2197,24 @@ impl ColumnWidths {
         self.uid += 2;
         self.gid += 2;
         self.size += 2;
+        self.allocated += 2;
+        self.items += 2;
         self.time += 2;
         self.file_type += 2;
         self.name += 2;
     }
 
     pub fn display_header(&self) {
-        println!(
-            "{:inode$}{:permissions$}{:links$}{:uid$}{:gid$}{:size$}{:time$}{:file_type$}{:name$}",
+        print!(
+            "{:inode$}{:permissions$}{:links$}{:uid$}{:gid$}{}{:items$}{:time$}{:file_type$}{:name$}",
             "Inode",
             "Permissions",
             "Links",
             "UID",
             "GID",
-            "Size",
-            "Modified",
+            self.size_columns("Size", "On disk"),
+            "Items",
+            "Last changed",
             "Type",
             "Name",
             inode = self.inode,
@@ -632,48 +2222,74 @@ impl ColumnWidths {
             links = self.links,
             uid = self.uid,
             gid = self.gid,
-            size = self.size,
+            items = self.items,
             time = self.time,
             file_type = self.file_type,
             name = self.name,
         );
+        if self.show_source {
+            print!("Source");
+        }
+        println!();
 
         let total_width = self.inode
             + self.permissions
             + self.links
             + self.uid
             + self.gid
-            + self.size
+            + self.size_columns("", "").len()
+            + self.items
             + self.time
             + self.file_type
-            + self.name;
+            + self.name
+            + if self.show_source { "Source".len() } else { 0 };
         println!("{}", "-".repeat(total_width));
     }
 
     pub fn display_file(&self, file: &FileInfo, size_format: &SizeFormat) {
-        let (inode, permissions, links, uid, gid, size, time, file_type) =
+        let (inode, permissions, links, uid, gid, size, allocated, items, time, file_type) =
             file.get_display_fields(size_format);
 
-        println!(
-            "{:inode$}{:permissions$}{:links$}{:uid$}{:gid$}{:size$}{:time$}{:file_type$}{:name$}",
+        print!(
+            "{:inode$}{:permissions$}{:links$}{:uid$}{:gid$}{}{:items$}{:time$}{:file_type$}{:name$}",
             inode,
             permissions,
             links,
             uid,
             gid,
-            size,
+            self.size_columns(&size, &allocated),
+            items,
             time,
             file_type,
-            file.name,
+            file.display_name(),
             inode = self.inode,
             permissions = self.permissions,
             links = self.links,
             uid = self.uid,
             gid = self.gid,
-            size = self.size,
+            items = self.items,
             time = self.time,
             file_type = self.file_type,
             name = self.name,
         );
+        if self.show_source {
+            print!("{}", file.format_size_source());
+        }
+        println!();
+    }
+
+    // The apparent size, allocated size or both, padded to their columns
+    fn size_columns(&self, size: &str, allocated: &str) -> String {
+        match self.size_mode {
+            SizeMode::Apparent => format!("{:width$}", size, width = self.size),
+            SizeMode::DiskUsage => format!("{:width$}", allocated, width = self.allocated),
+            SizeMode::Both => format!(
+                "{:size$}{:allocated$}",
+                size,
+                allocated,
+                size = self.size,
+                allocated = self.allocated
+            ),
+        }
     }
 }
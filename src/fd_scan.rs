@@ -0,0 +1,224 @@
+// Directory traversal through file descriptors, for scans on Linux. Each
+// directory is opened relative to the one it was found in and its entries are
+// looked at relative to it, so a path renamed mid-scan can't send the scan
+// somewhere else, and the kernel doesn't resolve the full path again for
+// every entry.
+
+use crate::data_structures::FileInfo;
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once statx turns out not to be there (kernels before 4.11, some sandboxes)
+static NO_STATX: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub struct Directory {
+    fd: OwnedFd,
+}
+
+// An entry as getdents64 reports it
+pub struct RawEntry {
+    pub name: CString,
+    pub kind: EntryKind,
+}
+
+// d_type of an entry; filesystems that don't fill it in report Unknown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory,
+    Symlink,
+    Other,
+    Unknown,
+}
+
+// The fields of a stat a scan uses
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub device: u64,
+    pub inode: u64,
+    pub mode: u32,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    // In 512-byte units, like st_blocks
+    pub blocks: u64,
+    // Nanoseconds since the Unix epoch
+    pub modified: u64,
+    pub changed: u64,
+}
+
+impl Directory {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        Self::open_raw(libc::AT_FDCWD, &path, true)
+    }
+
+    // A subdirectory by its name in this one; `follow` also opens it through a symlink
+    pub fn open_at(&self, name: &OsStr, follow: bool) -> io::Result<Self> {
+        let name = CString::new(name.as_bytes())?;
+        Self::open_raw(self.fd.as_raw_fd(), &name, follow)
+    }
+
+    fn open_raw(dirfd: libc::c_int, name: &CStr, follow: bool) -> io::Result<Self> {
+        let mut flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        if !follow {
+            flags |= libc::O_NOFOLLOW;
+        }
+        // SAFETY: name is a valid NUL-terminated string that outlives the call
+        let fd = unsafe { libc::openat(dirfd, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: fd is a freshly opened descriptor nothing else owns
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // Every entry but "." and "..", in directory order
+    pub fn entries(&self) -> io::Result<Vec<RawEntry>> {
+        // linux_dirent64: u64 d_ino, i64 d_off, u16 d_reclen, u8 d_type, name
+        const NAME_OFFSET: usize = 19;
+        let mut buffer = vec![0u8; 32 * 1024];
+        let mut entries = Vec::new();
+        loop {
+            // SAFETY: the buffer is valid for writes of its full length, and the
+            // kernel fills at most that many bytes with whole records
+            let read = unsafe {
+                libc::syscall(
+                    libc::SYS_getdents64,
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                )
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if read == 0 {
+                return Ok(entries);
+            }
+
+            let mut offset = 0;
+            while offset < read as usize {
+                let record_len =
+                    u16::from_ne_bytes([buffer[offset + 16], buffer[offset + 17]]) as usize;
+                let record = &buffer[offset..offset + record_len];
+                offset += record_len;
+
+                let name = CStr::from_bytes_until_nul(&record[NAME_OFFSET..])
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                if matches!(name.to_bytes(), b"." | b"..") {
+                    continue;
+                }
+                let kind = match record[18] {
+                    libc::DT_DIR => EntryKind::Directory,
+                    libc::DT_LNK => EntryKind::Symlink,
+                    libc::DT_UNKNOWN => EntryKind::Unknown,
+                    _ => EntryKind::Other,
+                };
+                entries.push(RawEntry {
+                    name: name.to_owned(),
+                    kind,
+                });
+            }
+        }
+    }
+
+    // The directory itself
+    pub fn stat(&self) -> io::Result<Stat> {
+        Self::stat_raw(self.fd.as_raw_fd(), c"", libc::AT_EMPTY_PATH)
+    }
+
+    // An entry of this directory; with `follow`, what a symlink points to
+    pub fn stat_at(&self, name: &CStr, follow: bool) -> io::Result<Stat> {
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        Self::stat_raw(self.fd.as_raw_fd(), name, flags | libc::AT_NO_AUTOMOUNT)
+    }
+
+    fn stat_raw(dirfd: libc::c_int, name: &CStr, flags: libc::c_int) -> io::Result<Stat> {
+        if !NO_STATX.load(Ordering::Relaxed) {
+            match Self::statx(dirfd, name, flags) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                    NO_STATX.store(true, Ordering::Relaxed);
+                }
+                result => return result,
+            }
+        }
+        Self::fstatat(dirfd, name, flags)
+    }
+
+    fn statx(dirfd: libc::c_int, name: &CStr, flags: libc::c_int) -> io::Result<Stat> {
+        // Everything but the access time, which nothing here looks at
+        const MASK: libc::c_uint = libc::STATX_TYPE
+            | libc::STATX_MODE
+            | libc::STATX_NLINK
+            | libc::STATX_UID
+            | libc::STATX_GID
+            | libc::STATX_INO
+            | libc::STATX_SIZE
+            | libc::STATX_BLOCKS
+            | libc::STATX_MTIME
+            | libc::STATX_CTIME;
+        // SAFETY: statx is plain data, for which all zeroes is a valid value
+        let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+        // SAFETY: name is NUL-terminated and buf is valid for writes of a statx
+        if unsafe { libc::statx(dirfd, name.as_ptr(), flags, MASK, &mut buf) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Stat {
+            device: libc::makedev(buf.stx_dev_major, buf.stx_dev_minor),
+            inode: buf.stx_ino,
+            mode: buf.stx_mode as u32,
+            nlink: buf.stx_nlink as u64,
+            uid: buf.stx_uid,
+            gid: buf.stx_gid,
+            size: buf.stx_size,
+            blocks: buf.stx_blocks,
+            modified: FileInfo::timestamp_to_nanos(
+                buf.stx_mtime.tv_sec,
+                buf.stx_mtime.tv_nsec as i64,
+            ),
+            changed: FileInfo::timestamp_to_nanos(
+                buf.stx_ctime.tv_sec,
+                buf.stx_ctime.tv_nsec as i64,
+            ),
+        })
+    }
+
+    fn fstatat(dirfd: libc::c_int, name: &CStr, flags: libc::c_int) -> io::Result<Stat> {
+        // SAFETY: stat is plain data, for which all zeroes is a valid value
+        let mut buf: libc::stat = unsafe { std::mem::zeroed() };
+        // SAFETY: name is NUL-terminated and buf is valid for writes of a stat
+        if unsafe { libc::fstatat(dirfd, name.as_ptr(), &mut buf, flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Stat {
+            device: buf.st_dev,
+            inode: buf.st_ino,
+            mode: buf.st_mode,
+            nlink: buf.st_nlink as u64,
+            uid: buf.st_uid,
+            gid: buf.st_gid,
+            size: buf.st_size as u64,
+            blocks: buf.st_blocks as u64,
+            modified: FileInfo::timestamp_to_nanos(buf.st_mtime, buf.st_mtime_nsec),
+            changed: FileInfo::timestamp_to_nanos(buf.st_ctime, buf.st_ctime_nsec),
+        })
+    }
+}
+
+impl Stat {
+    pub fn is_directory(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFLNK
+    }
+}
@@ -1,17 +1,44 @@
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+mod cache_command;
 mod data_structures;
 mod data_structures_impl;
+#[cfg(target_os = "linux")]
+mod fd_scan;
+mod mounts;
+mod watch;
 use data_structures::{
-    Cache, CacheEntry, ColumnWidths, FileInfo, Logger, SizeFormat, SizeUnit, Spinner,
+    ByteReader, Cache, CacheEntry, CacheKey, CacheLimits, ColumnWidths, ErrorCounts, FileInfo,
+    HardLink, Logger, ReadError, ScanOptions, SizeFormat, SizeMode, SizeSource, SizeUnit, Spinner,
+    SubtreeStats, SymlinkMode,
 };
+use sha2::{Digest, Sha256};
 
-// Cross-platform cache directory
+// Where the cache lived before it became per-user, migrated on first use
 #[cfg(unix)]
-const CACHE_DIR: &str = "/etc/lss";
+const LEGACY_CACHE_DIR: &str = "/etc/lss";
+
+#[cfg(unix)]
+fn get_cache_dir() -> PathBuf {
+    // XDG base directory spec: ignore the variable unless it is an absolute path
+    if let Some(xdg_cache) = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+    {
+        return xdg_cache.join("lss");
+    }
+    match env::var_os("HOME").filter(|home| !home.is_empty()) {
+        Some(home) => PathBuf::from(home).join(".cache").join("lss"),
+        None => PathBuf::from(LEGACY_CACHE_DIR),
+    }
+}
 
 #[cfg(windows)]
 fn get_cache_dir() -> PathBuf {
@@ -23,7 +50,75 @@ fn get_cache_dir() -> PathBuf {
     }
 }
 
+// Set by Ctrl-C or when --time-budget runs out. Scans stop descending and
+// leave what they found so far, and directory totals become lower bounds.
+pub static STOP_SCAN: AtomicBool = AtomicBool::new(false);
+// Whether it was Ctrl-C, which also changes the exit status
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// A second Ctrl-C doesn't wait for the scan to wind down
+#[cfg(unix)]
+extern "C" fn stop_scan_on_interrupt(_signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // SAFETY: _exit is async-signal-safe and skips the atexit handlers and
+        // buffered I/O that exit() would run in the middle of whatever was interrupted
+        unsafe { libc::_exit(130) };
+    }
+    STOP_SCAN.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn handle_interrupts() {
+    let handler = stop_scan_on_interrupt as extern "C" fn(libc::c_int);
+    // SAFETY: the handler has the signature signal() expects and only touches
+    // atomics or calls _exit, both of which are allowed in a signal handler
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(windows)]
+fn handle_interrupts() {
+    // Ctrl-C ends the process right away; nothing calculated is saved
+}
+
 const CACHE_FILE: &str = "global_cache.bin";
+const CACHE_TEMP_FILE: &str = "global_cache.bin.tmp";
+const CACHE_LOCK_FILE: &str = "global_cache.lock";
+
+// Cache file layout (all integers little-endian):
+//   header:  magic "LSSC", u16 version, u16 reserved, u64 entry count,
+//            u64 checksum (first 8 bytes of the SHA-256 of everything after the header)
+//   records: one fixed-width record per entry: u64 device id, u64 inode,
+//            u64 options tag, u64 size, u16 size unit, u64 mtime, u64 ctime,
+//            u64 local size, u64 last used (nanoseconds since the epoch),
+//            u16 path length, then subtree and local statistics, each as
+//            u64 files, u64 directories, u32 max depth, u64 newest mtime,
+//            u32 errors, then u64 parent device id, u64 parent inode,
+//            u32 child count, u64 filesystem id, u64 created, u64 allocated
+//            size, u64 local allocated size, u32 hard link count
+//   paths:   per record, the recorded directory path followed by its children's
+//            u64 device id and u64 inode, then its hard-linked files as
+//            u64 device id, u64 inode, u64 size, u64 allocated size,
+//            u32 link count, u32 links found,
+//            concatenated in record order
+//
+// Files written before the header existed are a bare stream of records:
+// u16 key length, key (a hex SHA-256 of the inode and device), u64 inode,
+// two padding bytes, u64 size, u16 size unit, u64 device id. They are still
+// read, with nothing to tell whether they are fresh, and get rewritten in the
+// current format on the next save.
+const CACHE_MAGIC: &[u8; 4] = b"LSSC";
+// Bumped on every change to the layout above, so a file from a build with
+// another layout is rejected instead of misread; 2 to 12 are taken
+const CACHE_VERSION: u16 = 13;
+const CACHE_HEADER_LEN: usize = 24;
+const CACHE_STATS_LEN: usize = 8 + 8 + 4 + 8 + 4;
+const CACHE_RECORD_LEN: usize =
+    8 + 8 + 8 + 8 + 2 + 8 + 8 + 8 + 8 + 2 + 2 * CACHE_STATS_LEN + 8 + 8 + 4 + 8 + 8 + 8 + 8 + 4;
+const CACHE_CHILD_LEN: usize = 8 + 8;
+const CACHE_LINK_LEN: usize = 8 + 8 + 8 + 8 + 4 + 4;
+const DEFAULT_CACHE_MAX_BYTES: u64 = 100 * 1024 * 1024;
 
 fn parse_size_format(format_str: &str) -> Result<SizeFormat, String> {
     match format_str.to_lowercase().as_str() {
@@ -34,6 +129,64 @@ fn parse_size_format(format_str: &str) -> Result<SizeFormat, String> {
     }
 }
 
+fn parse_byte_size(size_str: &str) -> Result<u64, String> {
+    let size_str = size_str.trim();
+    let split_at = size_str
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size_str.len());
+    let (number, unit) = size_str.split_at(split_at);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size: {}", size_str))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        _ => return Err(format!("Unknown size unit in: {}", size_str)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size too large: {}", size_str))
+}
+
+// Accepts a number of seconds with an optional unit: s, m, h, d or w
+fn parse_duration(duration_str: &str) -> Result<u64, String> {
+    let duration_str = duration_str.trim();
+    let split_at = duration_str
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration_str.len());
+    let (number, unit) = duration_str.split_at(split_at);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", duration_str))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return Err(format!("Unknown duration unit in: {}", duration_str)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Duration too long: {}", duration_str))
+}
+
+// Number of threads for -j; 0 means one per available CPU
+fn parse_jobs(jobs_str: &str) -> Result<usize, String> {
+    match jobs_str.trim().parse::<usize>() {
+        Ok(0) => Ok(std::thread::available_parallelism().map_or(1, |n| n.get())),
+        Ok(jobs) => Ok(jobs),
+        Err(_) => Err(format!("Invalid number of jobs: {}", jobs_str)),
+    }
+}
+
 fn parse_ignore_patterns(ignore_str: &str) -> Vec<String> {
     ignore_str
         .split(',')
@@ -42,181 +195,689 @@ fn parse_ignore_patterns(ignore_str: &str) -> Vec<String> {
         .collect()
 }
 
-fn ensure_cache_dir() -> io::Result<()> {
-    let cache_dir = get_cache_dir_path();
+fn ensure_cache_dir(cache_dir: &Path) -> io::Result<()> {
     if !cache_dir.exists() {
         fs::create_dir_all(cache_dir)?;
     }
     Ok(())
 }
 
+// Resolution order: --cache-dir, $LSS_CACHE_DIR, then the platform default
+fn get_cache_dir_path(cache_dir_override: Option<&Path>) -> PathBuf {
+    if let Some(cache_dir) = cache_dir_override {
+        return cache_dir.to_path_buf();
+    }
+    if let Some(cache_dir) = env::var_os("LSS_CACHE_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(cache_dir);
+    }
+    get_cache_dir()
+}
+
+fn get_cache_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join(CACHE_FILE)
+}
+
 #[cfg(unix)]
-fn get_cache_dir_path() -> PathBuf {
-    Path::new(CACHE_DIR).to_path_buf()
+fn migrate_legacy_cache(cache_dir: &Path, logger: &Logger) {
+    let legacy_path = Path::new(LEGACY_CACHE_DIR).join(CACHE_FILE);
+    let cache_path = get_cache_path(cache_dir);
+    if cache_path.exists() || !legacy_path.exists() || cache_path == legacy_path {
+        return;
+    }
+
+    let result = ensure_cache_dir(cache_dir).and_then(|_| fs::copy(&legacy_path, &cache_path));
+    match result {
+        Ok(_) => logger.info(&format!(
+            "Migrated cache from {} to {}",
+            legacy_path.display(),
+            cache_path.display()
+        )),
+        Err(e) => logger.warning(&format!(
+            "Could not migrate cache from {}: {}",
+            legacy_path.display(),
+            e
+        )),
+    }
 }
 
 #[cfg(windows)]
-fn get_cache_dir_path() -> PathBuf {
-    get_cache_dir()
+fn migrate_legacy_cache(_cache_dir: &Path, _logger: &Logger) {}
+
+fn cache_checksum(data: &[u8]) -> u64 {
+    let result = Sha256::digest(data);
+    u64::from_le_bytes([
+        result[0], result[1], result[2], result[3], result[4], result[5], result[6], result[7],
+    ])
 }
 
-fn get_cache_path() -> PathBuf {
-    get_cache_dir_path().join(CACHE_FILE)
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
 }
 
-fn save_cache(cache: &Cache, logger: &Logger) -> io::Result<()> {
-    ensure_cache_dir()?;
-    let cache_path = get_cache_path();
+#[cfg(windows)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
 
-    let mut file = File::create(&cache_path)?;
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
 
-    for (key, entry) in cache {
-        let key_bytes = key.as_bytes();
-        if key_bytes.len() > u16::MAX as usize {
-            logger.warning(&format!("Cache key too long, skipping: {}", key));
-            continue;
-        }
+#[cfg(windows)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
 
-        file.write_all(&(key_bytes.len() as u16).to_le_bytes())?;
-        file.write_all(key_bytes)?;
+fn encode_stats(buffer: &mut Vec<u8>, stats: &SubtreeStats) {
+    buffer.extend_from_slice(&stats.files.to_le_bytes());
+    buffer.extend_from_slice(&stats.dirs.to_le_bytes());
+    buffer.extend_from_slice(&stats.max_depth.to_le_bytes());
+    buffer.extend_from_slice(&stats.newest_modified.to_le_bytes());
+    buffer.extend_from_slice(&stats.errors.to_le_bytes());
+}
 
-        let inode_bytes = entry.inode.to_le_bytes();
-        file.write_all(&inode_bytes)?;
-        file.write_all(&[0u8; 2])?;
+fn decode_stats(reader: &mut ByteReader) -> Result<SubtreeStats, String> {
+    Ok(SubtreeStats {
+        files: reader.u64()?,
+        dirs: reader.u64()?,
+        max_depth: reader.u32()?,
+        newest_modified: reader.u64()?,
+        errors: reader.u32()?,
+    })
+}
 
-        let size_bytes = entry.size.to_le_bytes();
-        file.write_all(&size_bytes)?;
+fn encode_cache(cache: &Cache, logger: &Logger) -> Vec<u8> {
+    let entry_count = cache.len() + cache.detached_len();
+    let mut records = Vec::with_capacity(entry_count * CACHE_RECORD_LEN);
+    let mut paths = Vec::new();
 
-        let unit_bytes = entry.size_unit.to_u16().to_le_bytes();
-        file.write_all(&unit_bytes)?;
+    let detached = cache.detached.iter().map(|((_, key), entry)| (key, entry));
+    for (key, entry) in cache.entries.iter().chain(detached) {
+        let mut path_bytes = entry.path.as_deref().map(path_to_bytes).unwrap_or_default();
+        // Saved without a path, the directory can't be found again by name, so
+        // its parent gets listed each time instead
+        if path_bytes.len() > u16::MAX as usize {
+            logger.warning(&format!(
+                "Path too long to record in cache: {}",
+                String::from_utf8_lossy(&path_bytes)
+            ));
+            path_bytes.clear();
+        }
 
-        let device_bytes = entry.device_id.to_le_bytes();
-        file.write_all(&device_bytes)?;
+        records.extend_from_slice(&key.device.to_le_bytes());
+        records.extend_from_slice(&key.inode.to_le_bytes());
+        records.extend_from_slice(&key.options.to_le_bytes());
+        records.extend_from_slice(&entry.size.to_le_bytes());
+        records.extend_from_slice(&entry.size_unit.to_u16().to_le_bytes());
+        records.extend_from_slice(&entry.modified.to_le_bytes());
+        records.extend_from_slice(&entry.changed.to_le_bytes());
+        records.extend_from_slice(&entry.local_size.to_le_bytes());
+        records.extend_from_slice(&entry.last_used.to_le_bytes());
+        records.extend_from_slice(&(path_bytes.len() as u16).to_le_bytes());
+        encode_stats(&mut records, &entry.stats);
+        encode_stats(&mut records, &entry.local_stats);
+        // Parent and children share the entry's options, so only their device
+        // and inode are stored; (0, 0) stands for "no parent recorded"
+        let (parent_device, parent_inode) = entry
+            .parent
+            .map_or((0, 0), |parent| (parent.device, parent.inode));
+        records.extend_from_slice(&parent_device.to_le_bytes());
+        records.extend_from_slice(&parent_inode.to_le_bytes());
+        records.extend_from_slice(&(entry.children.len() as u32).to_le_bytes());
+        records.extend_from_slice(&entry.filesystem.to_le_bytes());
+        records.extend_from_slice(&entry.created.to_le_bytes());
+        records.extend_from_slice(&entry.allocated.to_le_bytes());
+        records.extend_from_slice(&entry.local_allocated.to_le_bytes());
+        records.extend_from_slice(&(entry.links.len() as u32).to_le_bytes());
+        paths.extend_from_slice(&path_bytes);
+        for child in &entry.children {
+            paths.extend_from_slice(&child.device.to_le_bytes());
+            paths.extend_from_slice(&child.inode.to_le_bytes());
+        }
+        for link in &entry.links {
+            paths.extend_from_slice(&link.device.to_le_bytes());
+            paths.extend_from_slice(&link.inode.to_le_bytes());
+            paths.extend_from_slice(&link.size.to_le_bytes());
+            paths.extend_from_slice(&link.allocated.to_le_bytes());
+            paths.extend_from_slice(&(link.nlink.min(u32::MAX as u64) as u32).to_le_bytes());
+            paths.extend_from_slice(&(link.found.min(u32::MAX as u64) as u32).to_le_bytes());
+        }
     }
+    records.extend_from_slice(&paths);
 
-    logger.info(&format!(
-        "Cache saved to: {} ({} entries)",
-        cache_path.display(),
-        cache.len()
-    ));
-    Ok(())
+    let mut data = Vec::with_capacity(CACHE_HEADER_LEN + records.len());
+    data.extend_from_slice(CACHE_MAGIC);
+    data.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&(entry_count as u64).to_le_bytes());
+    data.extend_from_slice(&cache_checksum(&records).to_le_bytes());
+    data.extend_from_slice(&records);
+    data
 }
 
-fn load_cache(logger: &Logger) -> io::Result<Cache> {
-    let cache_path = get_cache_path();
-    let mut cache = HashMap::new();
-
-    if !cache_path.exists() {
-        logger.info(&format!("No cache file found at: {}", cache_path.display()));
-        return Ok(cache);
+fn decode_cache_data(data: &[u8]) -> Result<Cache, String> {
+    if data.starts_with(CACHE_MAGIC) {
+        decode_cache(data)
+    } else {
+        decode_headerless_records(data)
     }
+}
 
-    let mut file = File::open(&cache_path)?;
-    let metadata = fs::metadata(&cache_path)?;
+fn decode_cache(data: &[u8]) -> Result<Cache, String> {
+    let mut header = ByteReader::new(data);
+    header.bytes(CACHE_MAGIC.len())?;
+    let version = header.u16()?;
+    header.u16()?;
+    let entry_count = header.u64()?;
+    let checksum = header.u64()?;
 
-    if metadata.len() == 0 {
-        logger.info("Cache file is empty");
-        return Ok(cache);
+    if version != CACHE_VERSION {
+        return Err(format!("unsupported format version {}", version));
     }
 
-    if metadata.len() > 100 * 1024 * 1024 {
-        logger.warning(&format!(
-            "Cache file too large ({} bytes), using empty cache",
-            metadata.len()
-        ));
-        return Ok(cache);
+    let body = &data[CACHE_HEADER_LEN..];
+    if cache_checksum(body) != checksum {
+        return Err("checksum mismatch".to_string());
     }
 
-    let mut corrupted_entries = 0;
+    let mut reader = ByteReader::new(body);
+    let records_len = usize::try_from(entry_count)
+        .ok()
+        .and_then(|count| count.checked_mul(CACHE_RECORD_LEN))
+        .ok_or_else(|| format!("implausible entry count {}", entry_count))?;
+    let records = reader.bytes(records_len)?;
 
-    loop {
-        let mut key_len_buf = [0u8; 2];
-        if file.read_exact(&mut key_len_buf).is_err() {
-            break;
+    let mut cache = Cache::new();
+    cache.entries.reserve(entry_count as usize);
+    for record in records.chunks_exact(CACHE_RECORD_LEN) {
+        let mut fields = ByteReader::new(record);
+        let key = CacheKey {
+            device: fields.u64()?,
+            inode: fields.u64()?,
+            options: fields.u64()?,
+        };
+        let size = fields.u64()?;
+        let size_unit = SizeUnit::from_u16(fields.u16()?).unwrap_or(SizeUnit::Bytes);
+        let modified = fields.u64()?;
+        let changed = fields.u64()?;
+        let local_size = fields.u64()?;
+        let last_used = fields.u64()?;
+        let path = match fields.u16()? as usize {
+            0 => None,
+            path_len => Some(path_from_bytes(reader.bytes(path_len)?)),
+        };
+        let stats = decode_stats(&mut fields)?;
+        let local_stats = decode_stats(&mut fields)?;
+
+        // Parent and children share the entry's options
+        let parent = CacheKey {
+            device: fields.u64()?,
+            inode: fields.u64()?,
+            options: key.options,
+        };
+        let parent = (parent.device != 0 || parent.inode != 0).then_some(parent);
+        let child_count = fields.u32()? as usize;
+        let mut child_keys = ByteReader::new(
+            reader.bytes(
+                child_count
+                    .checked_mul(CACHE_CHILD_LEN)
+                    .ok_or_else(|| format!("implausible child count {}", child_count))?,
+            )?,
+        );
+        let mut children = Vec::with_capacity(child_count);
+        for _ in 0..child_count {
+            children.push(CacheKey {
+                device: child_keys.u64()?,
+                inode: child_keys.u64()?,
+                options: key.options,
+            });
         }
-        let key_len = u16::from_le_bytes(key_len_buf) as usize;
 
-        if key_len == 0 || key_len > 4096 {
-            corrupted_entries += 1;
-            break;
+        let filesystem = fields.u64()?;
+        let created = fields.u64()?;
+        let allocated = fields.u64()?;
+        let local_allocated = fields.u64()?;
+
+        let link_count = fields.u32()? as usize;
+        let mut link_fields = ByteReader::new(
+            reader.bytes(
+                link_count
+                    .checked_mul(CACHE_LINK_LEN)
+                    .ok_or_else(|| format!("implausible hard link count {}", link_count))?,
+            )?,
+        );
+        let mut links = Vec::with_capacity(link_count);
+        for _ in 0..link_count {
+            links.push(HardLink {
+                device: link_fields.u64()?,
+                inode: link_fields.u64()?,
+                size: link_fields.u64()?,
+                allocated: link_fields.u64()?,
+                nlink: link_fields.u32()? as u64,
+                found: link_fields.u32()? as u64,
+            });
         }
 
-        let mut key_buf = vec![0u8; key_len];
-        if file.read_exact(&mut key_buf).is_err() {
-            corrupted_entries += 1;
-            break;
+        cache.restore(
+            key,
+            CacheEntry {
+                size,
+                size_unit,
+                modified,
+                changed,
+                local_size,
+                allocated,
+                local_allocated,
+                last_used,
+                created,
+                path,
+                stats,
+                local_stats,
+                parent,
+                children,
+                links,
+                filesystem,
+            },
+        );
+    }
+
+    if !reader.is_empty() {
+        return Err(format!(
+            "{} trailing bytes after {} entries",
+            reader.remaining(),
+            entry_count
+        ));
+    }
+    Ok(cache)
+}
+
+// Reads a file from before the header existed. Its entries carry no
+// timestamps, so they never count as fresh and get rescanned on first use.
+fn decode_headerless_records(data: &[u8]) -> Result<Cache, String> {
+    let mut reader = ByteReader::new(data);
+    let mut cache = Cache::new();
+    // Computed with whatever options that run had
+    let default_options = ScanOptions::default().cache_tag();
+
+    while !reader.is_empty() {
+        let key_len = reader.u16()? as usize;
+        if key_len == 0 || key_len > 4096 {
+            return Err(format!("invalid key length {}", key_len));
         }
-        let key = match String::from_utf8(key_buf) {
-            Ok(k) => k,
-            Err(_) => {
-                corrupted_entries += 1;
+        // The hex digest of the inode and device, both of which follow anyway
+        reader.bytes(key_len)?;
+        let inode = reader.u64()?;
+        reader.bytes(2)?;
+        let size = reader.u64()?;
+        let size_unit = SizeUnit::from_u16(reader.u16()?).unwrap_or(SizeUnit::Bytes);
+        let device = reader.u64()?;
+
+        cache.restore(
+            CacheKey {
+                device,
+                inode,
+                options: default_options,
+            },
+            CacheEntry {
+                size,
+                size_unit,
+                modified: 0,
+                changed: 0,
+                local_size: 0,
+                allocated: 0,
+                local_allocated: 0,
+                // Older than anything used since, so evicted first
+                last_used: 0,
+                created: 0,
+                path: None,
+                stats: SubtreeStats::default(),
+                local_stats: SubtreeStats::default(),
+                parent: None,
+                children: Vec::new(),
+                links: Vec::new(),
+                filesystem: 0,
+            },
+        );
+    }
+    Ok(cache)
+}
+
+// The entries of a directory given on the command line
+fn read_listing(
+    directory: &Path,
+    ignore_patterns: &[String],
+    follow_symlinks: bool,
+    logger: &Logger,
+    spinner: &mut Spinner,
+) -> io::Result<Vec<FileInfo>> {
+    let directory_device = FileInfo::device_of(directory)?;
+    let mut files = Vec::new();
+
+    logger.start_loading(spinner, "Scanning directory...");
+    let entries: Vec<_> = fs::read_dir(directory)?.collect();
+    let total_entries = entries.len();
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        // Listed, but with no name to show it under
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                files.push(FileInfo::unreadable(directory, "?".to_string(), &e));
                 continue;
             }
         };
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        logger.progress(spinner, index + 1, total_entries, "Scanning directory");
+
+        if FileInfo::should_ignore(&path, ignore_patterns) {
+            if logger.verbose {
+                println!("Ignoring: {}\t{}", name, path.display());
+            }
+            continue;
+        }
 
-        let mut inode_buf = [0u8; 10];
-        if file.read_exact(&mut inode_buf).is_err() {
-            corrupted_entries += 1;
-            break;
+        if logger.verbose {
+            println!("Loading entry: {}\t{}", name, path.display());
         }
-        let inode = u64::from_le_bytes([
-            inode_buf[0],
-            inode_buf[1],
-            inode_buf[2],
-            inode_buf[3],
-            inode_buf[4],
-            inode_buf[5],
-            inode_buf[6],
-            inode_buf[7],
-        ]);
 
-        let mut size_buf = [0u8; 8];
-        if file.read_exact(&mut size_buf).is_err() {
-            corrupted_entries += 1;
-            break;
+        match FileInfo::new(&path, name.clone(), follow_symlinks) {
+            Ok(mut file_info) => {
+                file_info.detect_mount_point(directory_device);
+                files.push(file_info);
+            }
+            Err(e) => files.push(FileInfo::unreadable(&path, name, &e)),
         }
-        let size = u64::from_le_bytes(size_buf);
+    }
+    logger.end_loading();
+    Ok(files)
+}
 
-        let mut unit_buf = [0u8; 2];
-        if file.read_exact(&mut unit_buf).is_err() {
-            corrupted_entries += 1;
-            break;
+// Puts the entries of each directory in `files` right after it, sorted the
+// same way, down to `levels` more levels. Symlinked directories are expanded
+// only when followed (-L), and with -x mount points aren't. `ancestors` holds
+// the (device, inode) of the directories above, so a link back up to one of
+// them isn't expanded again.
+//
+// What couldn't be read among the added entries goes into `errors`, unless
+// the size calculation that started at `scan_started` (None without one)
+// listed their directory and counted it already.
+#[allow(clippy::too_many_arguments)]
+fn expand_directories(
+    files: Vec<FileInfo>,
+    levels: usize,
+    ancestors: &mut Vec<(u64, u64)>,
+    cache: &Cache,
+    scan_options: &ScanOptions,
+    scan_started: Option<u64>,
+    sort_files: &dyn Fn(&mut Vec<FileInfo>),
+    errors: &mut ErrorCounts,
+    logger: &Logger,
+) -> Vec<FileInfo> {
+    let mut expanded = Vec::with_capacity(files.len());
+    for file in files {
+        let expand = levels > 0
+            && file.is_directory
+            && !(scan_options.one_file_system && file.is_mount_point);
+        if !expand {
+            expanded.push(file);
+            continue;
+        }
+        let key = file.get_cache_key(0);
+        if ancestors.contains(&(key.device, key.inode)) {
+            logger.warning(&format!(
+                "Not expanding '{}', it leads back to a directory above it",
+                file.name
+            ));
+            expanded.push(file);
+            continue;
         }
-        let unit_value = u16::from_le_bytes(unit_buf);
-        let size_unit = SizeUnit::from_u16(unit_value).unwrap_or(SizeUnit::Bytes);
 
-        let mut device_buf = [0u8; 8];
-        if file.read_exact(&mut device_buf).is_err() {
-            corrupted_entries += 1;
-            break;
+        let entries = read_nested_entries(&file, scan_options);
+        let listed_by_scan = scan_started.is_some_and(|started| {
+            cache
+                .get(&file.get_cache_key(scan_options.cache_tag()))
+                .is_some_and(|entry| entry.created >= started)
+        });
+        let name = file.name.clone();
+        let path = file.full_path.clone();
+        let stopped = matches!(file.size_source, Some(SizeSource::Stopped { .. }));
+        expanded.push(file);
+        match entries {
+            Ok(mut entries) => {
+                // Their sizes were calculated along with the directory above
+                for entry in &mut entries {
+                    if scan_started.is_some() {
+                        entry.load_cached_size(cache, scan_options, stopped);
+                    } else {
+                        entry.load_cached_stats(cache, scan_options);
+                    }
+                    if !listed_by_scan && let Some(error) = &entry.error {
+                        errors.record(error);
+                    }
+                }
+                sort_files(&mut entries);
+                ancestors.push((key.device, key.inode));
+                expanded.extend(expand_directories(
+                    entries,
+                    levels - 1,
+                    ancestors,
+                    cache,
+                    scan_options,
+                    scan_started,
+                    sort_files,
+                    errors,
+                    logger,
+                ));
+                ancestors.pop();
+            }
+            Err(e) => {
+                logger.warning(&format!("Could not list '{}': {}", name, e));
+                // A directory that can't be listed is never cached, so the
+                // size calculation tried it and counted it already
+                if scan_started.is_none() {
+                    errors.record(&ReadError::from_error(&e));
+                }
+                expanded.push(FileInfo::unreadable(&path, format!("{}/*", name), &e));
+            }
         }
-        let device_id = u64::from_le_bytes(device_buf);
+    }
+    expanded
+}
 
-        cache.insert(
-            key,
-            CacheEntry {
-                size,
-                inode,
-                device_id,
-                size_unit,
-            },
-        );
+// The entries of a directory in a recursive listing, named by their path
+// relative to the listed directory
+fn read_nested_entries(
+    directory: &FileInfo,
+    scan_options: &ScanOptions,
+) -> io::Result<Vec<FileInfo>> {
+    let parent_device = FileInfo::device_of(&directory.full_path)?;
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&directory.full_path)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let name = format!("{}/?", directory.name);
+                entries.push(FileInfo::unreadable(&directory.full_path, name, &e));
+                continue;
+            }
+        };
+        let path = entry.path();
+        if FileInfo::should_ignore(&path, &scan_options.ignore_patterns) {
+            continue;
+        }
+        let name = format!("{}/{}", directory.name, entry.file_name().to_string_lossy());
+        match FileInfo::new(&path, name.clone(), scan_options.follows_symlinks()) {
+            Ok(mut file_info) => {
+                file_info.detect_mount_point(parent_device);
+                entries.push(file_info);
+            }
+            Err(e) => entries.push(FileInfo::unreadable(&path, name, &e)),
+        }
     }
+    Ok(entries)
+}
+
+// Advisory lock shared by every lss process using this cache directory,
+// released when the returned file is dropped
+fn lock_cache(cache_dir: &Path, exclusive: bool) -> io::Result<File> {
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache_dir.join(CACHE_LOCK_FILE))?;
+    if exclusive {
+        lock_file.lock()?;
+    } else {
+        lock_file.lock_shared()?;
+    }
+    Ok(lock_file)
+}
+
+fn cache_record_len(entry: &CacheEntry) -> u64 {
+    let path_len = entry.path.as_ref().map_or(0, |path| path.as_os_str().len());
+    (CACHE_RECORD_LEN
+        + path_len
+        + entry.children.len() * CACHE_CHILD_LEN
+        + entry.links.len() * CACHE_LINK_LEN) as u64
+}
 
-    if corrupted_entries > 0 {
-        logger.warning(&format!(
-            "{} corrupted entries in cache file",
-            corrupted_entries
+fn save_cache(
+    cache: &mut Cache,
+    cache_dir: &Path,
+    limits: &CacheLimits,
+    logger: &Logger,
+) -> io::Result<()> {
+    ensure_cache_dir(cache_dir)?;
+    let cache_path = get_cache_path(cache_dir);
+
+    let _lock = lock_cache(cache_dir, true)?;
+
+    // Another lss run may have saved since we loaded; keep its entries
+    // instead of overwriting them with our older view of the file
+    let on_disk = read_cache_file(&cache_path, logger)?;
+    cache.merge(on_disk);
+
+    let evicted = cache.evict_to_fit(limits, cache_record_len);
+    if evicted > 0 {
+        logger.info(&format!(
+            "Evicted {} least recently used cache entries to stay within the cache limits",
+            evicted
         ));
     }
 
-    logger.info(&format!("Cache loaded: {} entries", cache.len()));
-    Ok(cache)
+    write_cache_file(cache, cache_dir, logger)?;
+    cache.dirty.clear();
+
+    logger.info(&format!(
+        "Cache saved to: {} ({} entries)",
+        cache_path.display(),
+        cache.len()
+    ));
+    Ok(())
+}
+
+// Replaces the cache file atomically; the caller must hold the exclusive lock
+fn write_cache_file(cache: &Cache, cache_dir: &Path, logger: &Logger) -> io::Result<()> {
+    let cache_path = get_cache_path(cache_dir);
+    let temp_path = cache_dir.join(CACHE_TEMP_FILE);
+
+    let data = encode_cache(cache, logger);
+    let write_result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(&data)?;
+        file.sync_all()
+    });
+    if let Err(e) = write_result.and_then(|_| fs::rename(&temp_path, &cache_path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    // Persist the rename itself; not every platform can open a directory for this
+    #[cfg(unix)]
+    if let Ok(dir) = File::open(cache_dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn load_cache(cache_dir: &Path, logger: &Logger) -> io::Result<Cache> {
+    let cache_path = get_cache_path(cache_dir);
+    if !cache_path.exists() {
+        logger.info(&format!("No cache file found at: {}", cache_path.display()));
+        return Ok(empty_cache());
+    }
+
+    // Saves replace the file atomically, so reading still works if the lock
+    // cannot be taken (e.g. a cache directory we may not write to)
+    let _lock = lock_cache(cache_dir, false).ok();
+    read_cache_file(&cache_path, logger)
+}
+
+// A cache with nothing loaded into it. It still needs the mounted filesystems,
+// or entries added to it would be saved without theirs.
+fn empty_cache() -> Cache {
+    let mut cache = Cache::new();
+    if let Some(filesystems) = mounts::filesystem_ids() {
+        cache.filesystems = filesystems;
+    }
+    cache
+}
+
+fn read_cache_file(cache_path: &Path, logger: &Logger) -> io::Result<Cache> {
+    if !cache_path.exists() {
+        return Ok(empty_cache());
+    }
+
+    let metadata = fs::metadata(cache_path)?;
+
+    if metadata.len() == 0 {
+        logger.info("Cache file is empty");
+        return Ok(empty_cache());
+    }
+
+    let data = fs::read(cache_path)?;
+    if !data.starts_with(CACHE_MAGIC) {
+        logger.info("Migrating cache file without header to the current format");
+    }
+
+    match decode_cache_data(&data) {
+        Ok(mut cache) => {
+            if let Some(filesystems) = mounts::filesystem_ids() {
+                cache.attach_filesystems(filesystems);
+            }
+            logger.info(&format!(
+                "Cache loaded: {} entries, {} for filesystems that are not mounted",
+                cache.len(),
+                cache.detached_len()
+            ));
+            Ok(cache)
+        }
+        Err(reason) => {
+            logger.warning(&format!(
+                "Cache file {} is corrupted ({}), rebuilding from scratch",
+                cache_path.display(),
+                reason
+            ));
+            Ok(empty_cache())
+        }
+    }
 }
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
 
+    if args.get(1).is_some_and(|arg| arg == "cache") {
+        return cache_command::run_cache_command(&args[0], &args[2..]);
+    }
+    if args.get(1).is_some_and(|arg| arg == "watch") {
+        return watch::run_watch_command(&args[0], &args[2..]);
+    }
+
     let mut sort_mode = "s";
     let mut reverse = false;
     let mut size_format = SizeFormat::Decimal;
@@ -225,6 +886,24 @@ fn main() -> std::io::Result<()> {
     let mut ignore_patterns: Vec<String> = Vec::new();
     let mut verbose = false;
     let mut ignore_symlinks = false;
+    let mut symlinks = SymlinkMode::Logical;
+    let mut cache_dir_override: Option<PathBuf> = None;
+    let mut cache_limits = CacheLimits {
+        max_entries: None,
+        max_bytes: DEFAULT_CACHE_MAX_BYTES,
+    };
+    let mut cache_max_age: Option<u64> = None;
+    let mut time_budget: Option<u64> = None;
+    let mut no_cache = false;
+    let mut cache_readonly = false;
+    let mut explain = false;
+    let mut jobs = 1;
+    let mut size_mode = SizeMode::Apparent;
+    let mut count_links = false;
+    let mut one_file_system = false;
+    // Levels listed, 1 being just the directory itself
+    let mut depth = 1;
+    let mut paths: Vec<PathBuf> = Vec::new();
 
     let mut i = 1;
     while i < args.len() {
@@ -240,6 +919,53 @@ fn main() -> std::io::Result<()> {
             "-rc" => recalculate_cache = true,
             "--verbose" => verbose = true,
             "--ignore-symlinks" => ignore_symlinks = true,
+            "-P" => symlinks = SymlinkMode::Physical,
+            "-H" => symlinks = SymlinkMode::CommandLine,
+            "-L" => symlinks = SymlinkMode::Logical,
+            "--no-cache" => no_cache = true,
+            "--explain" => explain = true,
+            "--cache-readonly" => cache_readonly = true,
+            "--apparent-size" => size_mode = SizeMode::Apparent,
+            "--disk-usage" => size_mode = SizeMode::DiskUsage,
+            "--both-sizes" => size_mode = SizeMode::Both,
+            "--count-links" => count_links = true,
+            "-x" | "--one-file-system" => one_file_system = true,
+            "-R" => depth = usize::MAX,
+            arg if arg.starts_with("--depth=") => match arg[8..].parse() {
+                Ok(levels) if levels > 0 => depth = levels,
+                _ => {
+                    eprintln!("Invalid depth: {}", &arg[8..]);
+                    return Ok(());
+                }
+            },
+            "-j" => {
+                i += 1;
+                match parse_jobs(args.get(i).map_or("", |value| value.as_str())) {
+                    Ok(value) => jobs = value,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Ok(());
+                    }
+                }
+            }
+            arg if arg.starts_with("--cache-max-age=") => match parse_duration(&arg[16..]) {
+                Ok(max_age) => cache_max_age = Some(max_age),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
+            arg if arg.starts_with("--time-budget=") => match parse_duration(&arg[14..]) {
+                Ok(0) => {
+                    eprintln!("Invalid time budget: {}", &arg[14..]);
+                    return Ok(());
+                }
+                Ok(budget) => time_budget = Some(budget),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
             arg if arg.starts_with("-sf=") => {
                 let format_str = &arg[4..];
                 match parse_size_format(format_str) {
@@ -253,6 +979,23 @@ fn main() -> std::io::Result<()> {
                     }
                 }
             }
+            arg if arg.starts_with("--cache-dir=") => {
+                cache_dir_override = Some(PathBuf::from(&arg[12..]));
+            }
+            arg if arg.starts_with("--cache-max-entries=") => match arg[20..].parse() {
+                Ok(max_entries) => cache_limits.max_entries = Some(max_entries),
+                Err(_) => {
+                    eprintln!("Invalid entry count: {}", &arg[20..]);
+                    return Ok(());
+                }
+            },
+            arg if arg.starts_with("--cache-max-size=") => match parse_byte_size(&arg[17..]) {
+                Ok(max_bytes) => cache_limits.max_bytes = max_bytes,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
             arg if arg.starts_with("--ignore=") => {
                 let ignore_str = &arg[9..];
                 ignore_patterns = parse_ignore_patterns(ignore_str);
@@ -260,23 +1003,86 @@ fn main() -> std::io::Result<()> {
                     println!("Ignore patterns: {:?}", ignore_patterns);
                 }
             }
+            // Everything after "--" is a path, even if it starts with '-'
+            "--" => {
+                paths.extend(args[i + 1..].iter().map(PathBuf::from));
+                break;
+            }
+            arg if !arg.starts_with('-') => paths.push(PathBuf::from(arg)),
             _ => {
                 eprintln!("Unknown option: {}", args[i]);
                 eprintln!(
-                    "Usage: {} [-s|-n|-t] [-r] [-ds] [-rc] [--verbose] [--ignore-symlinks] [-sf=FORMAT] [--ignore=PATTERNS]",
+                    "Usage: {} [-s|-n|-t] [-r] [-ds] [-rc] [--verbose] [-P|-H|-L] [--ignore-symlinks] [-sf=FORMAT] [--ignore=PATTERNS] [--cache-dir=DIR] [--cache-max-size=SIZE] [--cache-max-entries=N] [--cache-max-age=DURATION] [--time-budget=DURATION] [--cache-readonly] [--no-cache] [--explain] [-j N] [--apparent-size|--disk-usage|--both-sizes] [--count-links] [-x] [-R|--depth=N] [PATH...]",
+                    args[0]
+                );
+                eprintln!(
+                    "       {} cache <stats|prune|verify|clear|dump|warm ROOT> [--cache-dir=DIR]",
                     args[0]
                 );
+                eprintln!("       {} watch ROOT [--cache-dir=DIR]", args[0]);
+                eprintln!(
+                    "PATH: Directories to list and files to show (default: the current directory)"
+                );
                 eprintln!("Size formats: By, Bi, Kb, Mb, Gb, Tb");
                 eprintln!("-ds: Force directory size calculation (auto-enabled for size sorting)");
                 eprintln!(
                     "-rc: Recalculate cache (ignore existing cache and recalculate all sizes)"
                 );
                 eprintln!("--verbose: Enable verbose output with progress details");
-                eprintln!("--ignore-symlinks: Ignore symlinks when calculating directory sizes");
-                eprintln!("--ignore: Comma-separated list of files/directories to ignore");
+                eprintln!("-P: Don't follow symlinks; list and count them as the links themselves");
+                eprintln!(
+                    "-H: Follow symlinks given as paths on the command line, but not those found in directories"
+                );
+                eprintln!(
+                    "-L: Follow all symlinks (default); broken ones are listed and marked, links that lead back up are counted once"
+                );
+                eprintln!(
+                    "--ignore-symlinks: Don't follow symlinks and leave them out of directory sizes"
+                );
+                eprintln!(
+                    "--ignore: Comma-separated list of files/directories to ignore, also inside directory sizes"
+                );
                 eprintln!(
                     "          Example: --ignore=\".config/, myfile, mydir/, dir3/innerfile\""
                 );
+                eprintln!(
+                    "--cache-dir: Cache directory (default: $LSS_CACHE_DIR, $XDG_CACHE_HOME/lss or ~/.cache/lss)"
+                );
+                eprintln!(
+                    "--cache-max-size: Evict least recently used entries beyond this cache file size (default: 100MiB)"
+                );
+                eprintln!(
+                    "--cache-max-entries: Evict least recently used entries beyond this count"
+                );
+                eprintln!(
+                    "--cache-max-age: Recalculate directories cached longer ago than this (e.g. 90s, 30m, 12h, 7d)"
+                );
+                eprintln!(
+                    "--time-budget: Stop calculating directory sizes after this long (e.g. 5s, 2m); sizes not finished are shown as lower bounds"
+                );
+                eprintln!("--cache-readonly: Use the cache but never write to it");
+                eprintln!("--no-cache: Neither read nor write the cache");
+                eprintln!(
+                    "--explain: Show whether each directory size came from the cache, a fresh scan or is incomplete"
+                );
+                eprintln!("--apparent-size: Show and sort by file lengths (default)");
+                eprintln!(
+                    "--disk-usage: Show and sort by space allocated on disk, like du; sparse files count their allocated blocks only"
+                );
+                eprintln!("--both-sizes: Show both, sorted by apparent size");
+                eprintln!(
+                    "-x, --one-file-system: Don't calculate sizes across mount points; they are marked in the Type column either way"
+                );
+                eprintln!(
+                    "-R: List subdirectories recursively, each directory's entries sorted and right after it"
+                );
+                eprintln!("--depth: List N levels deep (1: the listed directory only)");
+                eprintln!(
+                    "--count-links: Count hard-linked files once per link instead of once per directory total"
+                );
+                eprintln!(
+                    "-j: Calculate directory sizes with up to N threads (default: 1, 0: one per CPU)"
+                );
                 return Ok(());
             }
         }
@@ -285,94 +1091,328 @@ fn main() -> std::io::Result<()> {
 
     let logger = Logger::new(verbose);
     let mut spinner = Spinner::new();
-    let mut cache = load_cache(&logger)?;
+    let cache_dir = get_cache_dir_path(cache_dir_override.as_deref());
+    let mut cache = if no_cache {
+        Cache::new()
+    } else {
+        if !cache_readonly {
+            migrate_legacy_cache(&cache_dir, &logger);
+        }
+        load_cache(&cache_dir, &logger)?
+    };
+    if let Some(max_age) = cache_max_age {
+        cache.set_max_age(max_age);
+    }
+    let scan_options = ScanOptions {
+        ignore_symlinks,
+        symlinks,
+        ignore_patterns: ignore_patterns.clone(),
+        count_links,
+        one_file_system,
+    };
 
     if verbose && ignore_symlinks {
         println!("Ignoring symlinks in directory size calculations");
     }
 
-    let current_dir = Path::new(".");
-    let mut files = Vec::new();
+    if paths.is_empty() {
+        paths.push(PathBuf::from("."));
+    }
 
-    logger.start_loading(&mut spinner, "Scanning directory...");
-    let entries: Vec<_> = fs::read_dir(current_dir)?.collect();
-    let total_entries = entries.len();
+    // Plain files given on the command line are shown together, ahead of the
+    // directories, like ls does
+    let mut file_operands = Vec::new();
+    let mut listings: Vec<(PathBuf, Vec<FileInfo>)> = Vec::new();
+    let mut failed = false;
+    for path in &paths {
+        let operand = FileInfo::new(
+            path,
+            path.display().to_string(),
+            scan_options.follows_command_line_symlinks(),
+        );
+        let listed = match operand {
+            Ok(file_info) if file_info.is_directory => read_listing(
+                path,
+                &ignore_patterns,
+                scan_options.follows_symlinks(),
+                &logger,
+                &mut spinner,
+            )
+            .map(|files| listings.push((path.clone(), files))),
+            Ok(file_info) => {
+                file_operands.push(file_info);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = listed {
+            eprintln!("lss: {}: {}", path.display(), e);
+            failed = true;
+        }
+    }
 
-    for (index, entry) in entries.into_iter().enumerate() {
-        let entry = entry?;
-        let path = entry.path();
-        let name = entry.file_name().to_string_lossy().to_string();
+    // When the size calculation started, if there is one
+    let mut scan_started = None;
+    if calculate_dir_sizes {
+        // Subtrees finished by then are cached and shown as usual
+        handle_interrupts();
+        if let Some(budget) = time_budget {
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(budget));
+                STOP_SCAN.store(true, Ordering::SeqCst);
+            });
+        }
+        logger.start_loading(&mut spinner, "Calculating directory sizes...");
+        scan_started = Some(FileInfo::system_time_to_nanos(&SystemTime::now()));
+        for (_, files) in &mut listings {
+            FileInfo::calculate_directory_sizes(
+                files,
+                &mut cache,
+                recalculate_cache,
+                &logger,
+                &scan_options,
+                jobs,
+            );
+        }
+        logger.end_loading();
+    } else {
+        for (_, files) in &mut listings {
+            for file_info in files {
+                file_info.load_cached_stats(&cache, &scan_options);
+            }
+        }
+    }
 
-        logger.progress(&mut spinner, index + 1, total_entries, "Scanning directory");
+    // The listing is still worth showing when the cache can't be written,
+    // e.g. a read-only cache directory in a container
+    if calculate_dir_sizes
+        && !no_cache
+        && !cache_readonly
+        && let Err(e) = save_cache(&mut cache, &cache_dir, &cache_limits, &logger)
+    {
+        eprintln!(
+            "Warning: Could not save cache to {}: {}",
+            get_cache_path(&cache_dir).display(),
+            e
+        );
+    }
 
-        if FileInfo::should_ignore(&path, &ignore_patterns) {
-            if verbose {
-                println!("Ignoring: {}\t{}", name, path.display());
-            }
-            continue;
+    let sort_files = |files: &mut Vec<FileInfo>| {
+        match sort_mode {
+            "s" => files.sort_by_key(|a| a.size_for(size_mode)),
+            "n" => files.sort_by(|a, b| a.name.cmp(&b.name)),
+            "t" => files.sort_by(|a, b| a.file_type.cmp(&b.file_type)),
+            _ => files.sort_by_key(|a| a.size_for(size_mode)),
         }
 
-        if verbose {
-            println!("Loading entry: {}\t{}", name, path.display());
+        if reverse {
+            files.reverse();
         }
+    };
+    let show_headings = paths.len() > 1;
+    let listed_paths = file_operands.len() + listings.len();
+    let mut groups: Vec<(Option<PathBuf>, Vec<FileInfo>)> = Vec::new();
+    if !file_operands.is_empty() {
+        groups.push((None, file_operands));
+    }
+    groups.extend(
+        listings
+            .into_iter()
+            .map(|(path, files)| (Some(path), files)),
+    );
 
-        if let Ok(mut file_info) = FileInfo::new(&path, name, ignore_symlinks) {
-            if calculate_dir_sizes && file_info.is_directory {
-                logger.start_loading(
-                    &mut spinner,
-                    &format!("Calculating size for: {}", file_info.name),
-                );
-                let mut visited_inodes = HashSet::new();
-                if let Err(e) = file_info.calculate_directory_size(
-                    &mut cache,
-                    recalculate_cache,
-                    &mut visited_inodes,
-                    &logger,
-                    ignore_symlinks,
-                ) {
-                    logger.warning(&format!(
-                        "Could not calculate size for directory '{}': {}",
-                        file_info.name, e
-                    ));
+    let (mut grand_total, mut grand_allocated, mut grand_items) = (0u64, 0u64, 0usize);
+    // Each file and directory counts once in the grand total, however many
+    // rows show it, and so does a file hard-linked from more than one row
+    let mut grand_rows: HashSet<(u64, u64)> = HashSet::new();
+    let mut grand_links: HashMap<(u64, u64), HardLink> = HashMap::new();
+    // Paths below a directory given on the command line are in its size
+    // already, so like du -c the grand total leaves them out; of two equal
+    // paths the first counts
+    let operand_directories: Vec<Option<PathBuf>> = groups
+        .iter()
+        .map(|(heading, _)| {
+            heading
+                .as_deref()
+                .and_then(|path| fs::canonicalize(path).ok())
+        })
+        .collect();
+    let counted_elsewhere = |path: &Path, group: usize| {
+        calculate_dir_sizes
+            && fs::canonicalize(path).is_ok_and(|path| {
+                operand_directories
+                    .iter()
+                    .enumerate()
+                    .any(|(other, directory)| {
+                        directory.as_deref().is_some_and(|directory| {
+                            other != group
+                                && path.starts_with(directory)
+                                && (path != directory || other < group)
+                        })
+                    })
+            })
+    };
+    // What couldn't be read, in the listing itself and in the scans below it
+    let mut errors = cache.counts.errors;
+    let mut incomplete = false;
+    for (index, (heading, mut files)) in groups.into_iter().enumerate() {
+        let group_counted = heading
+            .as_deref()
+            .is_none_or(|heading| !counted_elsewhere(heading, index));
+        for file in &files {
+            if !group_counted
+                || (heading.is_none() && counted_elsewhere(&file.full_path, index))
+                || (file.error.is_none() && !grand_rows.insert((file.device, file.inode)))
+            {
+                continue;
+            }
+            grand_total = grand_total.saturating_add(file.size);
+            grand_allocated = grand_allocated.saturating_add(file.allocated);
+            for link in file.hard_links(&scan_options) {
+                if grand_links
+                    .insert((link.device, link.inode), link)
+                    .is_some()
+                {
+                    grand_total = grand_total.saturating_sub(link.size);
+                    grand_allocated = grand_allocated.saturating_sub(link.allocated);
                 }
-                logger.end_loading();
             }
-            files.push(file_info);
         }
-    }
-    logger.end_loading();
+        for error in files.iter().filter_map(|file| file.error.as_ref()) {
+            errors.record(error);
+        }
 
-    if calculate_dir_sizes {
-        save_cache(&cache, &logger)?;
-    }
+        sort_files(&mut files);
+        if depth > 1 {
+            let mut ancestors: Vec<(u64, u64)> = heading
+                .as_deref()
+                .and_then(|path| FileInfo::cache_key_for_path(path, 0).ok())
+                .map(|key| (key.device, key.inode))
+                .into_iter()
+                .collect();
+            files = expand_directories(
+                files,
+                depth - 1,
+                &mut ancestors,
+                &cache,
+                &scan_options,
+                scan_started,
+                &sort_files,
+                &mut errors,
+                &logger,
+            );
+        }
+        grand_items += files.len();
 
-    match sort_mode {
-        "s" => files.sort_by(|a, b| a.size.cmp(&b.size)),
-        "n" => files.sort_by(|a, b| a.name.cmp(&b.name)),
-        "t" => files.sort_by(|a, b| a.file_type.cmp(&b.file_type)),
-        _ => files.sort_by(|a, b| a.size.cmp(&b.size)),
-    }
+        if index > 0 {
+            println!();
+        }
+        if show_headings && let Some(heading) = heading {
+            println!("{}:", heading.display());
+        }
 
-    if reverse {
-        files.reverse();
-    }
+        let mut col_widths = ColumnWidths::new();
+        col_widths.show_source = explain;
+        col_widths.size_mode = size_mode;
+        col_widths.calculate_from_files(&files, &size_format);
+        col_widths.display_header();
 
-    let mut col_widths = ColumnWidths::new();
-    col_widths.calculate_from_files(&files, &size_format);
-    col_widths.display_header();
+        for file in &files {
+            col_widths.display_file(file, &size_format);
+            incomplete |= file.is_incomplete();
+        }
 
-    for file in &files {
-        col_widths.display_file(&file, &size_format);
+        println!();
+        println!("Total items: {}", files.len());
     }
 
-    println!();
-    println!("Total items: {}", files.len());
+    if show_headings {
+        let total = match size_mode {
+            SizeMode::Apparent => FileInfo::format_bytes(grand_total, &size_format),
+            SizeMode::DiskUsage => format!(
+                "{} on disk",
+                FileInfo::format_bytes(grand_allocated, &size_format)
+            ),
+            SizeMode::Both => format!(
+                "{} ({} on disk)",
+                FileInfo::format_bytes(grand_total, &size_format),
+                FileInfo::format_bytes(grand_allocated, &size_format)
+            ),
+        };
+        println!();
+        println!(
+            "Grand total: {} in {} items across {} {}",
+            total,
+            grand_items,
+            listed_paths,
+            if listed_paths == 1 { "path" } else { "paths" }
+        );
+    }
     if calculate_dir_sizes {
-        if recalculate_cache {
+        if no_cache {
+            println!("Note: Cache disabled, all directory sizes were calculated");
+        } else if recalculate_cache && cache_readonly {
+            println!("Note: All directory sizes were recalculated, the cache was left unchanged");
+        } else if recalculate_cache {
             println!("Note: All directory sizes were recalculated and global cache was updated");
+        } else if let Some(max_age) = cache_max_age {
+            println!(
+                "Note: Directory sizes loaded from global cache where cached within the last {}s",
+                max_age
+            );
         } else {
             println!("Note: Directory sizes loaded from global cache where available");
         }
+        let counts = cache.counts;
+        let scanned = counts.reused + counts.listed;
+        if scanned > 0 {
+            println!(
+                "Cache: {} of {} directories unchanged and reused, {} listed ({:.0}% hit rate)",
+                counts.reused,
+                scanned,
+                counts.listed,
+                counts.reused as f64 * 100.0 / scanned as f64
+            );
+        }
+    }
+    if errors.total() > 0 {
+        println!(
+            "Errors: {} {} couldn't be read ({}){}",
+            errors.total(),
+            if errors.total() == 1 {
+                "entry"
+            } else {
+                "entries"
+            },
+            errors.summary(),
+            if verbose {
+                ""
+            } else {
+                ", --verbose shows where"
+            }
+        );
+    }
+    let skipped = cache.counts.skipped;
+    if skipped > 0 {
+        println!(
+            "Note: {} before {} {} could be scanned",
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                "Interrupted"
+            } else {
+                "Out of time"
+            },
+            skipped,
+            if skipped == 1 {
+                "directory"
+            } else {
+                "directories"
+            }
+        );
+    }
+    if incomplete {
+        println!(
+            "Note: Sizes marked ≥ are lower bounds, parts of those directories couldn't be read or weren't reached"
+        );
     }
     if ignore_symlinks {
         println!("Note: Symlinks were ignored in directory size calculations");
@@ -380,6 +1420,645 @@ fn main() -> std::io::Result<()> {
     if !ignore_patterns.is_empty() {
         println!("Ignored patterns: {:?}", ignore_patterns);
     }
-    println!("Global cache location: {}", get_cache_path().display());
+    if !no_cache {
+        println!(
+            "Global cache location: {}{}",
+            get_cache_path(&cache_dir).display(),
+            if cache_readonly { " (read-only)" } else { "" }
+        );
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        process::exit(130);
+    }
+    if failed {
+        process::exit(1);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_entry() -> CacheEntry {
+        CacheEntry {
+            size: 12_345,
+            size_unit: SizeUnit::Bytes,
+            modified: 1_700_000_000_000_000_000,
+            changed: 1_700_000_000_500_000_000,
+            local_size: 345,
+            allocated: 16_384,
+            local_allocated: 4_096,
+            last_used: 1_700_000_100_000_000_000,
+            created: 1_700_000_000_900_000_000,
+            path: Some(PathBuf::from("/home/user/project")),
+            stats: SubtreeStats {
+                files: 10,
+                dirs: 2,
+                max_depth: 2,
+                newest_modified: 1_700_000_000_000_000_000,
+                errors: 1,
+            },
+            local_stats: SubtreeStats {
+                files: 3,
+                dirs: 2,
+                max_depth: 0,
+                newest_modified: 1_699_000_000_000_000_000,
+                errors: 0,
+            },
+            parent: Some(CacheKey {
+                device: 2049,
+                inode: 2,
+                options: 7,
+            }),
+            children: vec![
+                CacheKey {
+                    device: 2049,
+                    inode: 101,
+                    options: 7,
+                },
+                CacheKey {
+                    device: 2049,
+                    inode: 102,
+                    options: 7,
+                },
+            ],
+            links: vec![HardLink {
+                device: 2049,
+                inode: 500,
+                size: 300,
+                allocated: 4_096,
+                nlink: 3,
+                found: 2,
+            }],
+            filesystem: 0,
+        }
+    }
+
+    // Scans share STOP_SCAN, which a test sets, so they take turns
+    static SCANS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn lock_scans() -> std::sync::MutexGuard<'static, ()> {
+        SCANS
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    // A directory of its own below the system temporary directory, removed
+    // with everything in it when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let path = env::temp_dir().join(format!(
+                "lss-test-{}-{}",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        // Writes a file of `len` bytes, creating the directories above it
+        fn file(&self, name: &str, len: usize) -> PathBuf {
+            let path = self.path(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![b'x'; len]).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Timestamps only move on every few milliseconds, so a change right after
+    // a scan could leave a directory's as they were. Setting its modification
+    // time back beforehand makes sure the change shows.
+    fn backdate(directory: &Path) {
+        File::open(directory)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+            .unwrap();
+    }
+
+    fn scan(path: &Path, cache: &mut Cache, options: &ScanOptions) -> FileInfo {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let mut directory =
+            FileInfo::new(path, name, options.follows_command_line_symlinks()).unwrap();
+        FileInfo::calculate_directory_sizes(
+            std::slice::from_mut(&mut directory),
+            cache,
+            false,
+            &Logger::new(false),
+            options,
+            1,
+        );
+        directory
+    }
+
+    // The cache as the next run finds it in the cache file
+    fn next_run(cache: &Cache) -> Cache {
+        decode_cache_data(&encode_cache(cache, &Logger::new(false))).unwrap()
+    }
+
+    fn cached_size(cache: &Cache, path: &Path, options: &ScanOptions) -> Option<u64> {
+        let key = FileInfo::cache_key_for_path(path, options.cache_tag()).unwrap();
+        cache.get(&key).map(CacheEntry::size_in_bytes)
+    }
+
+    // A record as lss wrote them before the cache file had a header
+    fn headerless_record(inode: u64, size: u64, size_unit: u16, device: u64) -> Vec<u8> {
+        let key = format!("{:064x}", inode ^ device);
+        let mut record = Vec::new();
+        record.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(&inode.to_le_bytes());
+        record.extend_from_slice(&[0u8; 2]);
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&size_unit.to_le_bytes());
+        record.extend_from_slice(&device.to_le_bytes());
+        record
+    }
+
+    #[test]
+    fn cache_survives_encoding() {
+        let key = CacheKey {
+            device: 2049,
+            inode: 100,
+            options: 7,
+        };
+        let mut cache = Cache::new();
+        cache.restore(key, sample_entry());
+        cache.restore(
+            CacheKey { inode: 101, ..key },
+            CacheEntry {
+                path: None,
+                parent: Some(key),
+                children: Vec::new(),
+                links: Vec::new(),
+                ..sample_entry()
+            },
+        );
+
+        let data = encode_cache(&cache, &Logger::new(false));
+        let decoded = decode_cache_data(&data).unwrap();
+        assert_eq!(decoded.entries, cache.entries);
+        assert!(decoded.detached.is_empty());
+    }
+
+    #[test]
+    fn cache_records_take_their_estimated_size() {
+        let key = CacheKey {
+            device: 2049,
+            inode: 100,
+            options: 7,
+        };
+        let mut entry = sample_entry();
+        entry.links = (0..1_000)
+            .map(|inode| HardLink {
+                inode,
+                ..entry.links[0]
+            })
+            .collect();
+        let mut cache = Cache::new();
+        cache.restore(key, entry.clone());
+
+        let data = encode_cache(&cache, &Logger::new(false));
+        assert_eq!(
+            data.len() as u64,
+            CACHE_HEADER_LEN as u64 + cache_record_len(&entry)
+        );
+        // Each hard-linked file costs a few dozen bytes on top of the record
+        assert_eq!(
+            cache_record_len(&entry) - cache_record_len(&sample_entry()),
+            999 * 40
+        );
+    }
+
+    #[test]
+    fn headerless_cache_is_read() {
+        let mut data = headerless_record(100, 3, SizeUnit::Kilobytes.to_u16(), 2049);
+        data.extend(headerless_record(101, 512, SizeUnit::Bytes.to_u16(), 2049));
+
+        let cache = decode_cache_data(&data).unwrap();
+        assert_eq!(cache.len(), 2);
+        let entry = cache
+            .get(&CacheKey {
+                device: 2049,
+                inode: 100,
+                options: ScanOptions::default().cache_tag(),
+            })
+            .unwrap();
+        assert_eq!(entry.size_in_bytes(), 3_000);
+        // Nothing says whether it is still fresh, so it never matches a directory
+        assert_eq!((entry.modified, entry.changed), (0, 0));
+    }
+
+    #[test]
+    fn headerless_cache_with_garbage_size_does_not_overflow() {
+        let data = headerless_record(100, u64::MAX / 2, SizeUnit::Tebibytes.to_u16(), 2049);
+        let cache = decode_cache_data(&data).unwrap();
+        let entry = cache.entries.values().next().unwrap();
+        assert_eq!(entry.size_in_bytes(), u64::MAX);
+    }
+
+    #[test]
+    fn truncated_headerless_cache_is_rejected() {
+        let data = headerless_record(100, 3, SizeUnit::Bytes.to_u16(), 2049);
+        assert!(decode_cache_data(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn cache_with_bad_checksum_is_rejected() {
+        let mut cache = Cache::new();
+        cache.restore(
+            CacheKey {
+                device: 2049,
+                inode: 100,
+                options: 7,
+            },
+            sample_entry(),
+        );
+        let mut data = encode_cache(&cache, &Logger::new(false));
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert_eq!(
+            decode_cache_data(&data).unwrap_err(),
+            "checksum mismatch".to_string()
+        );
+    }
+
+    #[test]
+    fn cache_of_another_version_is_rejected() {
+        let mut data = encode_cache(&Cache::new(), &Logger::new(false));
+        data[CACHE_MAGIC.len()..CACHE_MAGIC.len() + 2]
+            .copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+        assert!(decode_cache_data(&data).is_err());
+    }
+
+    #[test]
+    fn merge_keeps_only_our_changes() {
+        let key = |inode| CacheKey {
+            device: 2049,
+            inode,
+            options: 7,
+        };
+        let mut cache = Cache::new();
+        cache.restore(key(1), sample_entry());
+        cache.restore(key(2), sample_entry());
+        cache.insert(
+            key(3),
+            CacheEntry {
+                size: 1,
+                ..sample_entry()
+            },
+        );
+
+        // Another process removed 1 and 3 and rewrote 2 in the meantime
+        let mut on_disk = Cache::new();
+        on_disk.restore(
+            key(2),
+            CacheEntry {
+                size: 2,
+                ..sample_entry()
+            },
+        );
+        on_disk.restore(key(4), sample_entry());
+
+        cache.merge(on_disk);
+        assert!(cache.get(&key(1)).is_none());
+        assert_eq!(cache.get(&key(2)).map(|entry| entry.size), Some(2));
+        assert_eq!(cache.get(&key(3)).map(|entry| entry.size), Some(1));
+        assert!(cache.get(&key(4)).is_some());
+    }
+
+    #[test]
+    fn rescan_sees_a_file_added_below() {
+        let _scans = lock_scans();
+        let root = TempDir::new();
+        root.file("top", 100);
+        root.file("sub/a", 10);
+        backdate(&root.path("sub"));
+        backdate(&root.0);
+        let options = ScanOptions::default();
+        let mut cache = Cache::new();
+        assert_eq!(scan(&root.0, &mut cache, &options).size, 110);
+
+        root.file("sub/b", 5);
+        let mut cache = next_run(&cache);
+        let rescanned = scan(&root.0, &mut cache, &options);
+        assert_eq!(rescanned.size, 115);
+        assert_eq!(cached_size(&cache, &root.0, &options), Some(115));
+        // The top directory is unchanged, so only the one below is listed again
+        assert_eq!((cache.counts.listed, cache.counts.reused), (1, 1));
+        assert!(matches!(
+            rescanned.size_source,
+            Some(SizeSource::Updated {
+                rescanned: 1,
+                total: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn eviction_keeps_the_recently_used_within_the_budget() {
+        let _scans = lock_scans();
+        let root = TempDir::new();
+        for name in ["a/f", "b/f", "c/f"] {
+            root.file(name, 10);
+        }
+        let options = ScanOptions::default();
+        let mut cache = Cache::new();
+        scan(&root.0, &mut cache, &options);
+        assert_eq!(cache.len(), 4);
+
+        // Scanning it again makes it the most recently used
+        scan(&root.path("b"), &mut cache, &options);
+        let key = FileInfo::cache_key_for_path(&root.path("b"), options.cache_tag()).unwrap();
+        let limits = CacheLimits {
+            max_entries: None,
+            max_bytes: cache_record_len(cache.get(&key).unwrap()),
+        };
+        assert_eq!(cache.evict_to_fit(&limits, cache_record_len), 3);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key).is_some());
+        assert_eq!(cache.evict_to_fit(&limits, cache_record_len), 0);
+    }
+
+    #[test]
+    fn rescan_below_updates_cached_ancestors() {
+        let _scans = lock_scans();
+        let root = TempDir::new();
+        root.file("top", 100);
+        root.file("mid/sub/a", 10);
+        backdate(&root.path("mid/sub"));
+        let options = ScanOptions::default();
+        let mut cache = Cache::new();
+        assert_eq!(scan(&root.0, &mut cache, &options).size, 110);
+
+        root.file("mid/sub/b", 5);
+        assert_eq!(scan(&root.path("mid/sub"), &mut cache, &options).size, 15);
+        assert_eq!(cached_size(&cache, &root.path("mid"), &options), Some(15));
+        assert_eq!(cached_size(&cache, &root.0, &options), Some(115));
+        let key = FileInfo::cache_key_for_path(&root.0, options.cache_tag()).unwrap();
+        assert_eq!(cache.get(&key).unwrap().stats.files, 3);
+
+        // Nothing above is listed again to see it
+        let mut cache = next_run(&cache);
+        let rescanned = scan(&root.0, &mut cache, &options);
+        assert_eq!(rescanned.size, 115);
+        assert_eq!(cache.counts.listed, 0);
+        assert!(matches!(
+            rescanned.size_source,
+            Some(SizeSource::CacheHit { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn options_keep_separate_cache_entries() {
+        let _scans = lock_scans();
+        let target = TempDir::new();
+        target.file("data", 1_000);
+        let root = TempDir::new();
+        root.file("file", 100);
+        root.file("skip.log", 10);
+        std::os::unix::fs::symlink(&target.0, root.path("link")).unwrap();
+        let link_len = fs::symlink_metadata(root.path("link")).unwrap().len();
+
+        let logical = ScanOptions::default();
+        let physical = ScanOptions {
+            symlinks: SymlinkMode::Physical,
+            ..ScanOptions::default()
+        };
+        let ignoring = ScanOptions {
+            ignore_patterns: vec!["skip.log".to_string()],
+            ..ScanOptions::default()
+        };
+        let expected = [
+            (&logical, 1_110),
+            (&physical, 110 + link_len),
+            (&ignoring, 1_100),
+        ];
+        let mut cache = Cache::new();
+        for (options, size) in expected {
+            assert_eq!(scan(&root.0, &mut cache, options).size, size);
+        }
+
+        // Each finds its own entry, whatever ran before it
+        let mut cache = next_run(&cache);
+        for (options, size) in expected.into_iter().rev() {
+            assert_eq!(scan(&root.0, &mut cache, options).size, size);
+            assert_eq!(cached_size(&cache, &root.0, options), Some(size));
+        }
+        assert_eq!(cache.counts.listed, 0);
+    }
+
+    #[test]
+    fn entries_of_unmounted_filesystems_are_kept_aside() {
+        let _scans = lock_scans();
+        let root = TempDir::new();
+        root.file("sub/a", 10);
+        let options = ScanOptions::default();
+        let device = FileInfo::device_of(&root.0).unwrap();
+        let mut cache = Cache::new();
+        cache.attach_filesystems(HashMap::from([(device, 7)]));
+        scan(&root.0, &mut cache, &options);
+        let key = FileInfo::cache_key_for_path(&root.0, options.cache_tag()).unwrap();
+
+        // Unmounted, and then another filesystem under its device number
+        let mut cache = next_run(&cache);
+        cache.attach_filesystems(HashMap::new());
+        assert_eq!((cache.len(), cache.detached_len()), (0, 2));
+        let mut cache = next_run(&cache);
+        cache.attach_filesystems(HashMap::from([(device, 8)]));
+        assert!(cache.get(&key).is_none());
+
+        // Mounted again under another device number
+        let mut cache = next_run(&cache);
+        cache.attach_filesystems(HashMap::from([(device + 1, 7)]));
+        assert_eq!(cache.detached_len(), 0);
+        let moved = CacheKey {
+            device: device + 1,
+            ..key
+        };
+        let entry = cache.get(&moved).unwrap();
+        assert_eq!(entry.size_in_bytes(), 10);
+        assert_eq!(entry.children.len(), 1);
+        assert_eq!(entry.children[0].device, device + 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_count_once_across_directories() {
+        let _scans = lock_scans();
+        let root = TempDir::new();
+        let outside = TempDir::new();
+        let shared = root.file("a/f", 1_000);
+        fs::create_dir(root.path("b")).unwrap();
+        fs::hard_link(&shared, root.path("b/f")).unwrap();
+        fs::hard_link(&shared, outside.path("f")).unwrap();
+        root.file("c/g", 10);
+        let local = root.file("d/x", 100);
+        fs::hard_link(&local, root.path("d/y")).unwrap();
+
+        let options = ScanOptions::default();
+        let mut cache = Cache::new();
+        assert_eq!(scan(&root.0, &mut cache, &options).size, 1_110);
+        assert_eq!(scan(&root.path("b"), &mut cache, &options).size, 1_000);
+
+        // Only links with names outside the directory are recorded
+        let links = |name: &str| {
+            let key = FileInfo::cache_key_for_path(&root.path(name), options.cache_tag()).unwrap();
+            cache.get(&key).unwrap().links.len()
+        };
+        assert_eq!((links("a"), links("b"), links("d")), (1, 1, 0));
+
+        // Cached subtrees are deduplicated the same way
+        let mut cache = next_run(&cache);
+        let rescanned = scan(&root.0, &mut cache, &options);
+        assert_eq!(rescanned.size, 1_110);
+        assert_eq!(cache.counts.listed, 0);
+        // Its third name is outside, so the total above still needs it
+        assert_eq!(rescanned.subtree_links.len(), 1);
+
+        let count_links = ScanOptions {
+            count_links: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&root.0, &mut cache, &count_links).size, 2_210);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directory_totals_follow_the_symlink_mode() {
+        let _scans = lock_scans();
+        let elsewhere = TempDir::new();
+        elsewhere.file("more", 50);
+        let target = TempDir::new();
+        target.file("data", 1_000);
+        std::os::unix::fs::symlink(&elsewhere.0, target.path("nested")).unwrap();
+        let root = TempDir::new();
+        std::os::unix::fs::symlink(&target.0, root.path("link")).unwrap();
+        let link_len = |path: &Path| fs::symlink_metadata(path).unwrap().len();
+
+        let mode = |symlinks| ScanOptions {
+            symlinks,
+            ..ScanOptions::default()
+        };
+        let mut cache = Cache::new();
+        // -P lists the operand as the link it is
+        let physical = scan(&root.path("link"), &mut cache, &mode(SymlinkMode::Physical));
+        assert!(!physical.is_directory);
+        assert_eq!(physical.size, link_len(&root.path("link")));
+        // -H follows it, but not the links below it
+        let command_line = scan(
+            &root.path("link"),
+            &mut cache,
+            &mode(SymlinkMode::CommandLine),
+        );
+        assert_eq!(command_line.size, 1_000 + link_len(&target.path("nested")));
+        let logical = scan(&root.path("link"), &mut cache, &mode(SymlinkMode::Logical));
+        assert_eq!(logical.size, 1_050);
+    }
+
+    #[test]
+    fn stopped_scan_gives_lower_bounds() {
+        let _scans = lock_scans();
+        let root = TempDir::new();
+        root.file("sub/a", 10);
+        backdate(&root.0);
+        let options = ScanOptions::default();
+        let mut cache = Cache::new();
+        assert_eq!(scan(&root.0, &mut cache, &options).size, 10);
+
+        root.file("b", 100);
+        root.file("new/c", 1);
+        let mut cache = next_run(&cache);
+        STOP_SCAN.store(true, Ordering::SeqCst);
+        let stopped = scan(&root.0, &mut cache, &options);
+        STOP_SCAN.store(false, Ordering::SeqCst);
+        // Nothing was reached, which is all a lower bound can say
+        assert_eq!(stopped.size, 0);
+        assert!(matches!(
+            stopped.size_source,
+            Some(SizeSource::Stopped { skipped: 1 })
+        ));
+        assert_eq!(cache.counts.skipped, 1);
+        assert_eq!(cached_size(&cache, &root.0, &options), Some(10));
+
+        // Directories below it, as -R lists them, show what the cache knows
+        let below = |name: &str| {
+            let mut directory = FileInfo::new(&root.path(name), name.to_string(), true).unwrap();
+            directory.load_cached_size(&cache, &options, true);
+            directory
+        };
+        let cached = below("sub");
+        assert_eq!(cached.size, 10);
+        assert!(matches!(cached.size_source, Some(SizeSource::Included)));
+        let unknown = below("new");
+        assert_eq!(unknown.size, 0);
+        assert!(matches!(
+            unknown.size_source,
+            Some(SizeSource::Stopped { skipped: 1 })
+        ));
+
+        assert_eq!(scan(&root.0, &mut cache, &options).size, 111);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("90s"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1_800));
+        assert_eq!(parse_duration("12h"), Ok(43_200));
+        assert_eq!(parse_duration(" 7D "), Ok(604_800));
+        assert_eq!(parse_duration("2w"), Ok(1_209_600));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!(parse_byte_size("512"), Ok(512));
+        assert_eq!(parse_byte_size("512b"), Ok(512));
+        assert_eq!(parse_byte_size("2kb"), Ok(2_000));
+        assert_eq!(parse_byte_size("2K"), Ok(2_048));
+        assert_eq!(parse_byte_size("100MiB"), Ok(100 << 20));
+        assert_eq!(parse_byte_size("3 GB"), Ok(3_000_000_000));
+        assert_eq!(parse_byte_size("1t"), Ok(1 << 40));
+        assert!(parse_byte_size("").is_err());
+        assert!(parse_byte_size("12q").is_err());
+        assert!(parse_byte_size("1.5M").is_err());
+        assert!(parse_byte_size("99999999999TiB").is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(FileInfo::format_timestamp(0), "1970-01-01 00:00");
+        // Leap days, in a year divisible by 400 and an ordinary leap year
+        assert_eq!(
+            FileInfo::format_timestamp(951_825_599 * 1_000_000_000),
+            "2000-02-29 11:59"
+        );
+        assert_eq!(
+            FileInfo::format_timestamp(1_709_251_199 * 1_000_000_000 + 999_999_999),
+            "2024-02-29 23:59"
+        );
+        assert_eq!(
+            FileInfo::format_timestamp(1_735_689_600 * 1_000_000_000),
+            "2025-01-01 00:00"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+#[cfg(target_os = "linux")]
+use sha2::{Digest, Sha256};
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+
+// Maps the device id of every mounted filesystem to an id for the filesystem
+// itself: its UUID where known, otherwise its type, source and mount point.
+// Device numbers get reused by removable media and network mounts, these ids
+// don't. Returns None where the mount table cannot be read, so callers can
+// tell "not mounted" apart from "don't know".
+#[cfg(target_os = "linux")]
+pub fn filesystem_ids() -> Option<HashMap<u64, u64>> {
+    let content = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let uuids = device_uuids();
+
+    let mut filesystems = HashMap::new();
+    for (device, filesystem) in content
+        .lines()
+        .filter_map(|line| parse_mountinfo_line(line, &uuids))
+    {
+        // Bind mounts repeat a device; the first line is the original mount
+        filesystems.entry(device).or_insert(filesystem);
+    }
+    Some(filesystems)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn filesystem_ids() -> Option<HashMap<u64, u64>> {
+    None
+}
+
+// Block devices such as /dev/sda1 mapped to the UUID of the filesystem on them
+#[cfg(target_os = "linux")]
+fn device_uuids() -> HashMap<PathBuf, String> {
+    let Ok(entries) = fs::read_dir("/dev/disk/by-uuid") else {
+        return HashMap::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let device = fs::canonicalize(entry.path()).ok()?;
+            Some((device, entry.file_name().to_string_lossy().into_owned()))
+        })
+        .collect()
+}
+
+// Format: "36 35 98:0 /root /mnt rw,noatime master:1 - ext3 /dev/root rw"
+#[cfg(target_os = "linux")]
+fn parse_mountinfo_line(line: &str, uuids: &HashMap<PathBuf, String>) -> Option<(u64, u64)> {
+    let (mount, filesystem) = line.split_once(" - ")?;
+    let mut mount_fields = mount.split(' ');
+    let (major, minor) = mount_fields.nth(2)?.split_once(':')?;
+    let mount_point = mount_fields.nth(1)?;
+    let mut filesystem_fields = filesystem.split(' ');
+    let fs_type = filesystem_fields.next()?;
+    let source = filesystem_fields.next()?;
+
+    let uuid = Some(source)
+        .filter(|source| source.starts_with('/'))
+        .and_then(|source| fs::canonicalize(Path::new(source)).ok())
+        .and_then(|device| uuids.get(&device));
+    let identity = match uuid {
+        Some(uuid) => format!("uuid:{}", uuid),
+        None => format!("{}:{}:{}", fs_type, source, mount_point),
+    };
+
+    let hash = Sha256::digest(identity.as_bytes());
+    let filesystem_id = u64::from_le_bytes([
+        hash[0], hash[1], hash[2], hash[3], hash[4], hash[5], hash[6], hash[7],
+    ]);
+    // 0 stands for "unknown filesystem" in cache entries
+    Some((
+        make_device_id(major.parse().ok()?, minor.parse().ok()?),
+        filesystem_id.max(1),
+    ))
+}
+
+// Same encoding as glibc's makedev(), which is what st_dev holds
+#[cfg(target_os = "linux")]
+fn make_device_id(major: u64, minor: u64) -> u64 {
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo_lines() {
+        let uuids = HashMap::new();
+        let (device, filesystem) = parse_mountinfo_line(
+            "36 35 98:0 /root /mnt rw,noatime master:1 - ext3 /dev/no-such-disk rw",
+            &uuids,
+        )
+        .unwrap();
+        assert_eq!(device, make_device_id(98, 0));
+        assert_ne!(filesystem, 0);
+
+        // Optional fields vary in number; the filesystem part starts after " - "
+        let (device, other) = parse_mountinfo_line(
+            "40 1 0:45 / /media/usb rw,relatime shared:5 master:2 - vfat /dev/no-such-disk rw",
+            &uuids,
+        )
+        .unwrap();
+        assert_eq!(device, make_device_id(0, 45));
+        // Same source on another mount point and type is another filesystem
+        assert_ne!(other, filesystem);
+
+        assert_eq!(parse_mountinfo_line("", &uuids), None);
+        assert_eq!(
+            parse_mountinfo_line("36 35 98:0 /root /mnt rw", &uuids),
+            None
+        );
+    }
+
+    #[test]
+    fn device_ids_match_makedev() {
+        assert_eq!(make_device_id(8, 1), libc::makedev(8, 1));
+        assert_eq!(make_device_id(259, 70_000), libc::makedev(259, 70_000));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::data_structures::{CacheLimits, Logger, ScanOptions, SymlinkMode};
+use crate::{DEFAULT_CACHE_MAX_BYTES, get_cache_dir_path, migrate_legacy_cache};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const DEFAULT_SAVE_INTERVAL_SECS: u64 = 30;
+// Longest a change waits for the events to quiet down before it is rescanned
+// anyway, e.g. with a log file being appended to all the time
+const MAX_RESCAN_DELAY_SECS: u64 = 10;
+
+pub fn run_watch_command(program: &str, args: &[String]) -> io::Result<()> {
+    let mut root: Option<PathBuf> = None;
+    let mut cache_dir_override: Option<PathBuf> = None;
+    let mut verbose = false;
+    let mut save_interval = DEFAULT_SAVE_INTERVAL_SECS;
+    let mut jobs = 1;
+    let mut scan_options = ScanOptions::default();
+    let mut cache_limits = CacheLimits {
+        max_entries: None,
+        max_bytes: DEFAULT_CACHE_MAX_BYTES,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verbose" => verbose = true,
+            "-j" => match crate::parse_jobs(args.next().map_or("", |value| value.as_str())) {
+                Ok(value) => jobs = value,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            },
+            "--ignore-symlinks" => scan_options.ignore_symlinks = true,
+            "-P" => scan_options.symlinks = SymlinkMode::Physical,
+            "-H" => scan_options.symlinks = SymlinkMode::CommandLine,
+            "-L" => scan_options.symlinks = SymlinkMode::Logical,
+            "--count-links" => scan_options.count_links = true,
+            "-x" | "--one-file-system" => scan_options.one_file_system = true,
+            arg if arg.starts_with("--ignore=") => {
+                scan_options.ignore_patterns = crate::parse_ignore_patterns(&arg[9..]);
+            }
+            arg if arg.starts_with("--cache-dir=") => {
+                cache_dir_override = Some(PathBuf::from(&arg[12..]));
+            }
+            arg if arg.starts_with("--save-interval=") => match arg[16..].parse() {
+                Ok(secs) if secs > 0 => save_interval = secs,
+                _ => {
+                    eprintln!("Invalid save interval: {}", &arg[16..]);
+                    return Ok(());
+                }
+            },
+            arg if arg.starts_with("--cache-max-entries=") => match arg[20..].parse() {
+                Ok(max_entries) => cache_limits.max_entries = Some(max_entries),
+                Err(_) => {
+                    eprintln!("Invalid entry count: {}", &arg[20..]);
+                    return Ok(());
+                }
+            },
+            arg if arg.starts_with("--cache-max-size=") => {
+                match crate::parse_byte_size(&arg[17..]) {
+                    Ok(max_bytes) => cache_limits.max_bytes = max_bytes,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Ok(());
+                    }
+                }
+            }
+            arg if root.is_none() && !arg.starts_with('-') => root = Some(PathBuf::from(arg)),
+            _ => {
+                print_usage(program);
+                return Ok(());
+            }
+        }
+    }
+
+    let Some(root) = root else {
+        print_usage(program);
+        return Ok(());
+    };
+
+    let logger = Logger::new(verbose);
+    let cache_dir = get_cache_dir_path(cache_dir_override.as_deref());
+    migrate_legacy_cache(&cache_dir, &logger);
+
+    watch(
+        &root,
+        &cache_dir,
+        &scan_options,
+        &cache_limits,
+        save_interval,
+        jobs,
+        &logger,
+    )
+}
+
+fn print_usage(program: &str) {
+    eprintln!(
+        "Usage: {} watch ROOT [--cache-dir=DIR] [--save-interval=SECS] [-P|-H|-L] [--ignore-symlinks] [--ignore=PATTERNS] [--count-links] [-x] [--cache-max-size=SIZE] [--cache-max-entries=N] [-j N] [--verbose]",
+        program
+    );
+    eprintln!(
+        "Keeps the cached size of every directory below ROOT up to date as files change (Linux only)"
+    );
+    eprintln!(
+        "--save-interval: Seconds between cache saves while changes come in (default: {})",
+        DEFAULT_SAVE_INTERVAL_SECS
+    );
+    eprintln!("-j: Scan with up to N threads (default: 1, 0: one per CPU)");
+    eprintln!(
+        "Use the same -P/-H/-L, --ignore-symlinks, --ignore, --count-links and -x options as the listings it is for"
+    );
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(
+    _root: &Path,
+    _cache_dir: &Path,
+    _scan_options: &ScanOptions,
+    _cache_limits: &CacheLimits,
+    _save_interval: u64,
+    _jobs: usize,
+    _logger: &Logger,
+) -> io::Result<()> {
+    eprintln!("lss watch needs inotify and is only available on Linux");
+    process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+fn watch(
+    root: &Path,
+    cache_dir: &Path,
+    scan_options: &ScanOptions,
+    cache_limits: &CacheLimits,
+    save_interval: u64,
+    jobs: usize,
+    logger: &Logger,
+) -> io::Result<()> {
+    use crate::data_structures::{Cache, FileInfo};
+    use crate::{load_cache, save_cache};
+    use std::collections::HashSet;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    let root = std::path::absolute(root)?;
+    let mut root_info = match FileInfo::new(
+        &root,
+        root.display().to_string(),
+        scan_options.follows_command_line_symlinks(),
+    ) {
+        Ok(root_info) if root_info.is_directory => root_info,
+        Ok(_) => {
+            eprintln!("{}: not a directory", root.display());
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", root.display(), e);
+            process::exit(1);
+        }
+    };
+
+    let mut cache = load_cache(cache_dir, logger)?;
+    let mut inotify = Inotify::new(&root)?;
+    let watched = inotify.watch_tree(&root, scan_options, logger);
+
+    // Ctrl-C stops a rescan that is under way like it stops a listing, and
+    // ends the loop below so the cache is saved before exiting
+    crate::handle_interrupts();
+
+    // Bring the cache up to date before relying on events for changes
+    root_info.calculate_directory_size(&mut cache, false, logger, scan_options, jobs);
+    save_cache(&mut cache, cache_dir, cache_limits, logger)?;
+    println!(
+        "Watching {} ({} directories), press Ctrl-C to stop",
+        root.display(),
+        watched
+    );
+
+    let save_interval = Duration::from_secs(save_interval);
+    let max_rescan_delay = Duration::from_secs(MAX_RESCAN_DELAY_SECS);
+    let mut last_save = Instant::now();
+    let mut pending: HashSet<PathBuf> = HashSet::new();
+    // When the oldest change in `pending` came in
+    let mut pending_since = Instant::now();
+    let mut unsaved = 0usize;
+
+    // A file rewritten in place doesn't touch its directory's timestamps, so
+    // drop the cached freshness of every directory an event came from, under
+    // any set of options
+    let invalidate = |cache: &mut Cache, directories: &[PathBuf]| {
+        let keys: HashSet<(u64, u64)> = directories
+            .iter()
+            .filter_map(|directory| std::fs::symlink_metadata(directory).ok())
+            .map(|metadata| (metadata.dev(), metadata.ino()))
+            .collect();
+        cache.invalidate(&keys);
+    };
+
+    while !crate::STOP_SCAN.load(Ordering::SeqCst) {
+        // Changes tend to come in bursts, so wait for a quiet second before
+        // rescanning the directories they touched
+        let changes = inotify.read_changes(Duration::from_secs(1), logger)?;
+        let quiet = changes.directories.is_empty() && !changes.overflowed;
+        if pending.is_empty() {
+            pending_since = Instant::now();
+        }
+        pending.extend(changes.directories);
+
+        for directory in &changes.new_directories {
+            inotify.watch_tree(directory, scan_options, logger);
+        }
+        if changes.overflowed {
+            logger.warning("Too many changes at once, rescanning the whole tree");
+            inotify.watch_tree(&root, scan_options, logger);
+            pending.insert(root.clone());
+        }
+        if inotify.is_empty() {
+            eprintln!("{} is gone, stopping", root.display());
+            break;
+        }
+
+        if !pending.is_empty() && (quiet || pending_since.elapsed() >= max_rescan_delay) {
+            // Deepest first, so each rescan finds the directories below it
+            // already up to date
+            let mut directories: Vec<PathBuf> = pending.drain().collect();
+            directories.sort_by_key(|directory| std::cmp::Reverse(directory.components().count()));
+            invalidate(&mut cache, &directories);
+
+            for directory in &directories {
+                let name = directory
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                // Gone already; the event on its parent covers it
+                let Ok(mut directory_info) =
+                    FileInfo::new(directory, name, scan_options.follows_symlinks())
+                else {
+                    continue;
+                };
+                directory_info.calculate_directory_size(
+                    &mut cache,
+                    false,
+                    logger,
+                    scan_options,
+                    jobs,
+                );
+                logger.info(&format!("Updated {}", directory.display()));
+            }
+            unsaved += directories.len();
+        }
+
+        if unsaved > 0 && last_save.elapsed() >= save_interval {
+            save_cache(&mut cache, cache_dir, cache_limits, logger)?;
+            logger.info(&format!(
+                "Saved cache after updating {} directories",
+                unsaved
+            ));
+            unsaved = 0;
+            last_save = Instant::now();
+        }
+    }
+
+    // Changes that came in but weren't rescanned yet get rescanned by the
+    // next run that needs them
+    if !pending.is_empty() {
+        let directories: Vec<PathBuf> = pending.drain().collect();
+        invalidate(&mut cache, &directories);
+        unsaved += directories.len();
+    }
+    if unsaved > 0 {
+        save_cache(&mut cache, cache_dir, cache_limits, logger)?;
+        logger.info(&format!(
+            "Saved cache after updating {} directories",
+            unsaved
+        ));
+    }
+    if crate::INTERRUPTED.load(Ordering::SeqCst) {
+        process::exit(130);
+    }
+    Ok(())
+}
+
+// Directories an inotify read reported changes in
+#[cfg(target_os = "linux")]
+struct Changes {
+    directories: Vec<PathBuf>,
+    // Created or moved in, so they need watches of their own
+    new_directories: Vec<PathBuf>,
+    // The kernel dropped events, so anything may have changed
+    overflowed: bool,
+}
+
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: std::os::fd::OwnedFd,
+    root: PathBuf,
+    // Watch descriptors and the directories they were added for
+    watches: std::collections::HashMap<i32, PathBuf>,
+    limit_reported: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR
+        | libc::IN_DONT_FOLLOW;
+
+    fn new(root: &Path) -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: inotify_init1 takes no pointers
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            // SAFETY: fd is a freshly created descriptor nothing else owns
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+            root: root.to_path_buf(),
+            watches: std::collections::HashMap::new(),
+            limit_reported: false,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    // Watches `directory` and every directory below it, without following
+    // symlinks. Returns how many directories are watched in total.
+    fn watch_tree(
+        &mut self,
+        directory: &Path,
+        scan_options: &ScanOptions,
+        logger: &Logger,
+    ) -> usize {
+        use crate::data_structures::FileInfo;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::MetadataExt;
+
+        // With -x, the filesystem the root is on is the only one watched
+        let root_device = std::fs::metadata(&self.root)
+            .ok()
+            .filter(|_| scan_options.one_file_system)
+            .map(|metadata| metadata.dev());
+
+        let mut stack = vec![directory.to_path_buf()];
+        while let Some(directory) = stack.pop() {
+            let Ok(path) = std::ffi::CString::new(directory.as_os_str().as_bytes()) else {
+                continue;
+            };
+            // SAFETY: path is a valid NUL-terminated string that outlives the call
+            let wd = unsafe {
+                libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), Self::EVENTS)
+            };
+            if wd < 0 {
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() == Some(libc::ENOSPC) {
+                    if !self.limit_reported {
+                        eprintln!(
+                            "Warning: out of inotify watches, changes below some directories will be missed (raise fs.inotify.max_user_watches)"
+                        );
+                        self.limit_reported = true;
+                    }
+                } else {
+                    logger.warning(&format!(
+                        "Could not watch '{}': {}",
+                        directory.display(),
+                        error
+                    ));
+                }
+                continue;
+            }
+            self.watches.insert(wd, directory.clone());
+
+            let Ok(entries) = std::fs::read_dir(&directory) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+                    && !FileInfo::should_ignore(&path, &scan_options.ignore_patterns)
+                    && root_device.is_none_or(|device| {
+                        entry
+                            .metadata()
+                            .is_ok_and(|metadata| metadata.dev() == device)
+                    })
+                {
+                    stack.push(path);
+                }
+            }
+        }
+        self.watches.len()
+    }
+
+    // Waits up to `timeout` for events and collects what they touched
+    fn read_changes(
+        &mut self,
+        timeout: std::time::Duration,
+        logger: &Logger,
+    ) -> io::Result<Changes> {
+        use std::os::fd::AsRawFd;
+
+        let mut changes = Changes {
+            directories: Vec::new(),
+            new_directories: Vec::new(),
+            overflowed: false,
+        };
+
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: poll_fd is a single valid pollfd for the duration of the call
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            return if error.kind() == io::ErrorKind::Interrupted {
+                Ok(changes)
+            } else {
+                Err(error)
+            };
+        }
+        if ready == 0 {
+            return Ok(changes);
+        }
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            // SAFETY: the buffer is valid for writes of its full length
+            let read = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(error);
+            }
+            self.parse_events(&buffer[..read as usize], &mut changes, logger);
+        }
+        Ok(changes)
+    }
+
+    // Each event is a struct inotify_event (wd, mask, cookie, len) followed
+    // by `len` bytes of NUL-padded name
+    fn parse_events(&mut self, mut events: &[u8], changes: &mut Changes, logger: &Logger) {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        const HEADER_LEN: usize = 16;
+        while events.len() >= HEADER_LEN {
+            let field = |offset: usize| {
+                u32::from_ne_bytes([
+                    events[offset],
+                    events[offset + 1],
+                    events[offset + 2],
+                    events[offset + 3],
+                ])
+            };
+            let wd = field(0) as i32;
+            let mask = field(4);
+            let name_len = field(12) as usize;
+            let Some(name) = events.get(HEADER_LEN..HEADER_LEN + name_len) else {
+                break;
+            };
+            let name = &name[..name
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(name.len())];
+            events = &events[HEADER_LEN + name_len..];
+
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                changes.overflowed = true;
+                continue;
+            }
+            let Some(directory) = self.watches.get(&wd).cloned() else {
+                continue;
+            };
+            if mask & libc::IN_IGNORED != 0 {
+                // The directory was deleted, moved away or unmounted
+                self.watches.remove(&wd);
+                continue;
+            }
+            if mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                // A directory moved within the tree is watched again under its
+                // new path when its new parent reports it
+                // SAFETY: plain syscall on our own inotify fd
+                unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+                self.watches.remove(&wd);
+                if let Some(parent) = directory.parent()
+                    && parent.starts_with(&self.root)
+                {
+                    changes.directories.push(parent.to_path_buf());
+                }
+                continue;
+            }
+
+            logger.info(&format!(
+                "Change in {}: {}",
+                directory.display(),
+                String::from_utf8_lossy(name)
+            ));
+            if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                changes
+                    .new_directories
+                    .push(directory.join(std::ffi::OsStr::from_bytes(name)));
+            }
+            changes.directories.push(directory);
+        }
+    }
+}